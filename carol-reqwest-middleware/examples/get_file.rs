@@ -16,12 +16,12 @@ async fn main() {
         .expect("init storage manager");
     let reqwest_client = reqwest::Client::builder().build().unwrap();
     let client = reqwest_middleware::ClientBuilder::new(reqwest_client)
-        .with(CarolMiddleware {
+        .with(CarolMiddleware::new(
             storage_manager,
-            store_policy: StorePolicy::ExpiresAfterNotUsedFor {
+            StorePolicy::ExpiresAfterNotUsedFor {
                 duration: Duration::from_secs(3600),
             },
-        })
+        ))
         .build();
 
     let response = client.get(&url).send().await.expect("get URL");
@@ -0,0 +1,151 @@
+//! Bodies of responses returned by the middleware.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use carol::{StorageDatabaseError, StorageError};
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
+use reqwest_middleware::reqwest::{self, Body};
+use tokio::sync::mpsc;
+
+/// Capacity of the channel between the tee and the response body, in chunks.
+const TEE_CAPACITY: usize = 16;
+
+/// Body of successful responses to `GET` requests handled by the middleware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyMode {
+    /// JSON-serialized [`File`](carol::File) describing the stored file.
+    ///
+    /// The response is returned after the file is completely stored.
+    #[default]
+    Metadata,
+
+    /// Content of the file, as if there was no middleware.
+    ///
+    /// The response is returned as soon as the origin responds, its body yields content while
+    /// the file is being stored. Requests for a file, which is being stored, read the growing
+    /// file instead of waiting for it.
+    Transparent,
+}
+
+/// Chunk of a response body.
+pub(crate) type BodyChunk = Result<Bytes, io::Error>;
+
+/// Error yielded to storage by a body, which exceeds maximum size of stored responses.
+#[derive(thiserror::Error, Debug)]
+#[error("response body exceeds maximum size of {0} bytes")]
+pub(crate) struct SizeLimitExceeded(pub u64);
+
+/// Check if storing failed, because the body exceeded maximum size.
+pub(crate) fn exceeds_size_limit<E: StorageDatabaseError>(err: &StorageError<E>) -> bool {
+    let StorageError::CustomError(err) = err else {
+        return false;
+    };
+    err.downcast_ref::<io::Error>()
+        .and_then(io::Error::get_ref)
+        .is_some_and(|err| err.is::<SizeLimitExceeded>())
+}
+
+/// Yield chunks of `origin` stream until their total size exceeds `max_size`, then yield
+/// [`SizeLimitExceeded`] error.
+pub(crate) fn limit_size<S>(origin: S, max_size: Option<u64>) -> impl Stream<Item = BodyChunk>
+where
+    S: Stream<Item = reqwest::Result<Bytes>>,
+{
+    let mut size = 0;
+    origin.map(move |chunk| {
+        let chunk = chunk.map_err(io::Error::other)?;
+        size += chunk.len() as u64;
+        match max_size {
+            Some(max_size) if size > max_size => Err(io::Error::other(SizeLimitExceeded(max_size))),
+            _ => Ok(chunk),
+        }
+    })
+}
+
+/// State of the stream returned by [`tee`].
+struct Tee<S> {
+    /// Origin stream, taken when storing stops.
+    origin: Option<S>,
+    sender: mpsc::Sender<BodyChunk>,
+    consumed: Arc<AtomicBool>,
+    max_size: Option<u64>,
+    size: u64,
+}
+
+/// Split `origin` stream into a stream for storage and a receiver of the same chunks.
+///
+/// Chunks are passed to the receiver as they are read from the returned stream. If the receiver
+/// is dropped, the stream keeps yielding chunks, so storing is not interrupted. Errors are not
+/// passed to the receiver. `consumed` is set when the returned stream reaches the end.
+///
+/// If total size of chunks exceeds `max_size`, the returned stream yields [`SizeLimitExceeded`]
+/// error, and the rest of `origin` is passed to the receiver directly.
+pub(crate) fn tee<S>(
+    origin: S,
+    max_size: Option<u64>,
+    consumed: Arc<AtomicBool>,
+) -> (
+    BoxStream<'static, BodyChunk>,
+    mpsc::Sender<BodyChunk>,
+    mpsc::Receiver<BodyChunk>,
+)
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+{
+    let (sender, receiver) = mpsc::channel(TEE_CAPACITY);
+    let state = Tee {
+        origin: Some(origin),
+        sender: sender.clone(),
+        consumed,
+        max_size,
+        size: 0,
+    };
+    let stream = futures_util::stream::unfold(state, async |mut state| {
+        let origin = state.origin.as_mut()?;
+        match origin.next().await {
+            Some(Ok(chunk)) => {
+                state.size += chunk.len() as u64;
+                // Caller may drop the body, this must not stop storing
+                let _ = state.sender.send(Ok(chunk.clone())).await;
+                if let Some(max_size) = state.max_size.filter(|max| state.size > *max) {
+                    let origin = state.origin.take()?;
+                    tokio::spawn(forward(origin, state.sender.clone()));
+                    let err = io::Error::other(SizeLimitExceeded(max_size));
+                    return Some((Err(err), state));
+                }
+                Some((Ok(chunk), state))
+            }
+            Some(Err(err)) => Some((Err(io::Error::other(err)), state)),
+            None => {
+                state.consumed.store(true, Ordering::Release);
+                None
+            }
+        }
+    });
+    (Box::pin(stream), sender, receiver)
+}
+
+/// Pass the rest of `origin` stream to `sender` until the receiver is dropped.
+async fn forward<S>(mut origin: S, sender: mpsc::Sender<BodyChunk>)
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+{
+    while let Some(chunk) = origin.next().await {
+        if sender.send(chunk.map_err(io::Error::other)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Create body from `prefix` followed by chunks from `receiver`.
+pub(crate) fn body_from_channel(
+    prefix: impl Stream<Item = BodyChunk> + Send + 'static,
+    mut receiver: mpsc::Receiver<BodyChunk>,
+) -> Body {
+    let received = futures_util::stream::poll_fn(move |context| receiver.poll_recv(context));
+    Body::wrap_stream(prefix.chain(received))
+}
@@ -0,0 +1,117 @@
+//! `Cache-Control` header parsing (RFC 9111, section 5.2).
+
+use http::header::CACHE_CONTROL;
+use http::HeaderMap;
+
+/// Directives of `Cache-Control` header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CacheControl {
+    /// Directive names (lowercase) with optional arguments.
+    directives: Vec<(String, Option<String>)>,
+}
+
+impl CacheControl {
+    /// Collect directives from all `Cache-Control` headers.
+    ///
+    /// Header values, which are not valid strings, are ignored.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut directives = Vec::new();
+        for value in headers.get_all(CACHE_CONTROL) {
+            if let Ok(value) = value.to_str() {
+                directives.extend(parse_directives(value));
+            }
+        }
+        Self { directives }
+    }
+
+    /// Check if the directive is present.
+    pub fn has(&self, name: &str) -> bool {
+        self.directives.iter().any(|(n, _)| n == name)
+    }
+
+    /// Get argument of the first directive with given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.directives
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, arg)| arg.as_deref())
+    }
+
+    /// Get argument of the directive as a number of seconds (delta-seconds).
+    ///
+    /// Returns `None` if the directive is missing or its argument is not a valid number.
+    pub fn seconds(&self, name: &str) -> Option<u64> {
+        self.get(name)?.parse().ok()
+    }
+}
+
+/// Split header value into directives. Commas inside quoted arguments are respected.
+fn parse_directives(value: &str) -> Vec<(String, Option<String>)> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ',' if !quoted => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+
+    parts
+        .iter()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .map(|part| match part.split_once('=') {
+            Some((name, arg)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(arg.trim().trim_matches('"').to_string()),
+            ),
+            None => (part.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CacheControl;
+    use http::header::CACHE_CONTROL;
+    use http::HeaderMap;
+    use rstest::rstest;
+
+    fn cache_control(values: &[&str]) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(CACHE_CONTROL, value.parse().unwrap());
+        }
+        CacheControl::from_headers(&headers)
+    }
+
+    #[rstest]
+    #[case(&[], "no-store", false)]
+    #[case(&["no-store"], "no-store", true)]
+    #[case(&["No-Store"], "no-store", true)]
+    #[case(&["public, max-age=60"], "max-age", true)]
+    #[case(&["public", "no-cache"], "no-cache", true)]
+    #[case(&["no-cache=\"a, no-store\""], "no-store", false)]
+    #[trace]
+    fn test_has(#[case] values: &[&str], #[case] name: &str, #[case] expected: bool) {
+        assert_eq!(cache_control(values).has(name), expected);
+    }
+
+    #[rstest]
+    #[case(&[], "max-age", None)]
+    #[case(&["max-age=60"], "max-age", Some(60))]
+    #[case(&["public", "Stale-If-Error=\"300\""], "stale-if-error", Some(300))]
+    #[case(&["max-age"], "max-age", None)]
+    #[case(&["max-age=soon"], "max-age", None)]
+    #[case(&["max-age=60, max-age=120"], "max-age", Some(60))]
+    #[trace]
+    fn test_seconds(#[case] values: &[&str], #[case] name: &str, #[case] expected: Option<u64>) {
+        assert_eq!(cache_control(values).seconds(name), expected);
+    }
+}
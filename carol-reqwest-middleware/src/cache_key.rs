@@ -0,0 +1,244 @@
+//! Cache keys of requests.
+//!
+//! Cache key is a [`FileSource`] under which the response is stored. By default it is the
+//! request URL. Values of selected request headers are folded into the key as a SHA256 suffix,
+//! so responses varying by these headers don't collide and header values (which may be secret,
+//! e.g. `Authorization`) never appear in storage in plain text.
+
+use std::collections::HashMap;
+
+use carol::FileSource;
+use http::header::VARY;
+use http::{HeaderMap, HeaderName};
+use reqwest_middleware::reqwest::Request;
+
+/// Function computing base cache key of the request.
+pub type CacheKeyFn = dyn Fn(&Request) -> FileSource + Send + Sync;
+
+/// Prefix of the suffix holding the hash of request headers.
+const KEY_SUFFIX_PREFIX: &str = "carol-key=";
+
+/// Default base cache key: request URL.
+pub(crate) fn default_cache_key(request: &Request) -> FileSource {
+    FileSource::from(request.url().clone())
+}
+
+/// Fold values of `headers` taken from `request_headers` into `source`.
+///
+/// `headers` are expected to be sorted and deduplicated. If `headers` is empty, `source` is
+/// returned unchanged. Otherwise the digest of header values is appended: URL sources get it
+/// as a fragment (replacing existing one, which is never sent to the server anyway), custom
+/// sources get it after `#`.
+pub(crate) fn fold_headers(
+    source: FileSource,
+    headers: &[HeaderName],
+    request_headers: &HeaderMap,
+) -> FileSource {
+    if headers.is_empty() {
+        return source;
+    }
+    let mut input = String::new();
+    for name in headers {
+        input.push_str(name.as_str());
+        let mut values = request_headers.get_all(name).iter().peekable();
+        if values.peek().is_some() {
+            input.push(':');
+            for (i, value) in values.enumerate() {
+                if i > 0 {
+                    input.push_str(", ");
+                }
+                input.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        input.push('\n');
+    }
+    let suffix = format!("{}{}", KEY_SUFFIX_PREFIX, sha256::digest(input));
+    match source {
+        FileSource::Url(mut url) => {
+            url.set_fragment(Some(&suffix));
+            FileSource::Url(url)
+        }
+        FileSource::Custom(custom) => FileSource::Custom(format!("{}#{}", custom, suffix)),
+    }
+}
+
+/// Get names of headers listed in `Vary` header of the response.
+///
+/// Returns `None` if the response varies on `*`, i.e. can't be matched by request headers.
+pub(crate) fn vary_headers(response_headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut headers = Vec::new();
+    for value in response_headers.get_all(VARY) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for name in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::try_from(name) {
+                headers.push(name);
+            }
+        }
+    }
+    Some(headers)
+}
+
+/// Merge lists of header names into a sorted list without duplicates.
+pub(crate) fn merge_headers<'a>(
+    lists: impl IntoIterator<Item = &'a [HeaderName]>,
+) -> Vec<HeaderName> {
+    let mut headers: Vec<HeaderName> = lists.into_iter().flatten().cloned().collect();
+    headers.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    headers.dedup();
+    headers
+}
+
+/// Default number of base keys [`VaryCache`] remembers `Vary` headers for.
+pub(crate) const DEFAULT_VARY_CAPACITY: usize = 1024;
+
+/// Headers listed in `Vary` of responses, remembered per base cache key.
+///
+/// At most `capacity` keys are remembered; the least recently used one is forgotten when a new
+/// key is added to a full cache.
+#[derive(Debug)]
+pub(crate) struct VaryCache {
+    capacity: usize,
+    entries: HashMap<FileSource, (u64, Vec<HeaderName>)>,
+    clock: u64,
+}
+
+impl VaryCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Get headers remembered for `base`, marking it as recently used.
+    pub(crate) fn get(&mut self, base: &FileSource) -> Option<&[HeaderName]> {
+        self.clock += 1;
+        let (used, headers) = self.entries.get_mut(base)?;
+        *used = self.clock;
+        Some(headers)
+    }
+
+    /// Remember `headers` for `base`. Empty `headers` forget `base`.
+    pub(crate) fn insert(&mut self, base: &FileSource, headers: Vec<HeaderName>) {
+        if headers.is_empty() || self.capacity == 0 {
+            self.entries.remove(base);
+            return;
+        }
+        self.clock += 1;
+        if !self.entries.contains_key(base) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(source, _)| source.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(base.clone(), (self.clock, headers));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_headers, merge_headers, vary_headers, VaryCache};
+    use carol::FileSource;
+    use http::header::{ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, VARY};
+    use http::{HeaderMap, HeaderName};
+    use rstest::rstest;
+
+    fn headers(values: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_fold_no_headers() {
+        let source = FileSource::parse("https://example.com/file");
+        let request_headers = headers(&[(ACCEPT, "text/plain")]);
+        assert_eq!(fold_headers(source.clone(), &[], &request_headers), source);
+    }
+
+    #[rstest]
+    #[case("https://example.com/file")]
+    #[case("https://example.com/file#fragment")]
+    #[case("somesource")]
+    #[trace]
+    fn test_fold_headers_roundtrip(#[case] input: &str) {
+        let source = FileSource::parse(input);
+        let request_headers = headers(&[(AUTHORIZATION, "Bearer secret")]);
+        let folded = fold_headers(source, &[AUTHORIZATION], &request_headers);
+        assert!(!folded.as_str().contains("secret"));
+        assert!(folded.as_str().contains("#carol-key="));
+        // Folded key survives storing in database as a string
+        assert_eq!(FileSource::parse(folded.as_str()), folded);
+    }
+
+    #[rstest]
+    #[case::same(&[(ACCEPT, "text/plain")], &[(ACCEPT, "text/plain")], true)]
+    #[case::different_values(&[(ACCEPT, "text/plain")], &[(ACCEPT, "text/html")], false)]
+    #[case::missing(&[(ACCEPT, "")], &[], false)]
+    #[case::unrelated_header(&[(ACCEPT, "text/plain")], &[(ACCEPT, "text/plain"), (AUTHORIZATION, "secret")], true)]
+    #[trace]
+    fn test_fold_headers_matching(
+        #[case] first: &[(HeaderName, &str)],
+        #[case] second: &[(HeaderName, &str)],
+        #[case] expected_equal: bool,
+    ) {
+        let source = FileSource::parse("https://example.com/file");
+        let first = fold_headers(source.clone(), &[ACCEPT], &headers(first));
+        let second = fold_headers(source, &[ACCEPT], &headers(second));
+        assert_eq!(first == second, expected_equal);
+    }
+
+    #[rstest]
+    #[case(&[], Some(vec![]))]
+    #[case(&["Accept"], Some(vec![ACCEPT]))]
+    #[case(&["accept, Accept-Encoding"], Some(vec![ACCEPT, ACCEPT_ENCODING]))]
+    #[case(&["accept", "accept-encoding"], Some(vec![ACCEPT, ACCEPT_ENCODING]))]
+    #[case(&["accept, *"], None)]
+    #[trace]
+    fn test_vary_headers(#[case] values: &[&str], #[case] expected: Option<Vec<HeaderName>>) {
+        let response_headers = headers(
+            &values
+                .iter()
+                .map(|value| (VARY, *value))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(vary_headers(&response_headers), expected);
+    }
+
+    #[test]
+    fn test_merge_headers() {
+        let merged = merge_headers([&[ACCEPT_ENCODING, ACCEPT][..], &[AUTHORIZATION, ACCEPT][..]]);
+        assert_eq!(merged, vec![ACCEPT, ACCEPT_ENCODING, AUTHORIZATION]);
+    }
+
+    #[test]
+    fn test_vary_cache_evicts_least_recently_used() {
+        let first = FileSource::parse("https://example.com/first");
+        let second = FileSource::parse("https://example.com/second");
+        let third = FileSource::parse("https://example.com/third");
+        let mut cache = VaryCache::new(2);
+        cache.insert(&first, vec![ACCEPT]);
+        cache.insert(&second, vec![ACCEPT_ENCODING]);
+        assert_eq!(cache.get(&first), Some(&[ACCEPT][..]));
+        cache.insert(&third, vec![AUTHORIZATION]);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get(&second), None);
+        assert_eq!(cache.get(&first), Some(&[ACCEPT][..]));
+        assert_eq!(cache.get(&third), Some(&[AUTHORIZATION][..]));
+        cache.insert(&third, vec![]);
+        assert_eq!(cache.get(&third), None);
+        assert_eq!(cache.entries.len(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+//! Rules defining which requests and responses can be cached (RFC 9111, section 3).
+
+use http::header::{CONTENT_TYPE, EXPIRES};
+use http::{HeaderMap, Method, StatusCode};
+
+use crate::cache_control::CacheControl;
+use crate::cache_key::vary_headers;
+
+/// Status codes, which are cacheable by default (RFC 9110, section 15.1).
+const HEURISTICALLY_CACHEABLE: [StatusCode; 11] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::PERMANENT_REDIRECT,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::GONE,
+    StatusCode::URI_TOO_LONG,
+    StatusCode::NOT_IMPLEMENTED,
+];
+
+/// Status codes of negative results, which may be recorded in cache.
+const NEGATIVE: [StatusCode; 2] = [StatusCode::NOT_FOUND, StatusCode::GONE];
+
+/// Check if the request can be answered from cache.
+///
+/// Only `GET` and `HEAD` requests are handled by the cache. Other methods are not safe and
+/// their responses are passed through untouched.
+pub(crate) fn is_cacheable_method(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// Check if the response to a `GET` request may be stored.
+///
+/// The response must have a final status code, must not be forbidden to store with `no-store`
+/// directive in either the request or the response, must not vary on `*`, and must either have
+/// a heuristically cacheable status code or contain explicit freshness information.
+///
+/// Partial content (`206`) and `304 Not Modified` responses are never stored, because they do
+/// not carry the complete representation.
+pub(crate) fn is_storable(
+    request_cache_control: &CacheControl,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> bool {
+    if status.is_informational()
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    let response_cache_control = CacheControl::from_headers(headers);
+    if request_cache_control.has("no-store") || response_cache_control.has("no-store") {
+        return false;
+    }
+    if vary_headers(headers).is_none() {
+        return false;
+    }
+    HEURISTICALLY_CACHEABLE.contains(&status)
+        || response_cache_control.has("public")
+        || response_cache_control.has("max-age")
+        || headers.contains_key(EXPIRES)
+}
+
+/// Check if the response status means that the resource does not exist.
+pub(crate) fn is_negative_status(status: StatusCode) -> bool {
+    NEGATIVE.contains(&status)
+}
+
+/// Check if the response content type matches one of `allowed` types.
+///
+/// Allowed types may use `*` subtype wildcard, e.g. `text/*`. Parameters of the content type
+/// are ignored, types are compared case-insensitively.
+pub(crate) fn is_allowed_content_type(allowed: &[String], headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    allowed
+        .iter()
+        .any(|allowed| match allowed.strip_suffix("/*") {
+            Some(main_type) => essence
+                .split_once('/')
+                .is_some_and(|(type_, _)| type_.eq_ignore_ascii_case(main_type)),
+            None => essence.eq_ignore_ascii_case(allowed),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_allowed_content_type, is_cacheable_method, is_negative_status, is_storable};
+    use crate::cache_control::CacheControl;
+    use http::header::{CACHE_CONTROL, CONTENT_TYPE, EXPIRES, VARY};
+    use http::{HeaderMap, HeaderName, Method, StatusCode};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Method::GET, true)]
+    #[case(Method::HEAD, true)]
+    #[case(Method::POST, false)]
+    #[case(Method::PUT, false)]
+    #[case(Method::DELETE, false)]
+    #[case(Method::OPTIONS, false)]
+    #[trace]
+    fn test_is_cacheable_method(#[case] method: Method, #[case] expected: bool) {
+        assert_eq!(is_cacheable_method(&method), expected);
+    }
+
+    #[rstest]
+    #[case(StatusCode::NOT_FOUND, true)]
+    #[case(StatusCode::GONE, true)]
+    #[case(StatusCode::OK, false)]
+    #[case(StatusCode::FORBIDDEN, false)]
+    #[case(StatusCode::INTERNAL_SERVER_ERROR, false)]
+    #[trace]
+    fn test_is_negative_status(#[case] status: StatusCode, #[case] expected: bool) {
+        assert_eq!(is_negative_status(status), expected);
+    }
+
+    #[rstest]
+    #[case::ok(StatusCode::OK, &[], &[], true)]
+    #[case::no_content(StatusCode::NO_CONTENT, &[], &[], true)]
+    #[case::moved_permanently(StatusCode::MOVED_PERMANENTLY, &[], &[], true)]
+    #[case::partial_content(StatusCode::PARTIAL_CONTENT, &[], &[], false)]
+    #[case::not_modified(StatusCode::NOT_MODIFIED, &[], &[], false)]
+    #[case::created(StatusCode::CREATED, &[], &[], false)]
+    #[case::created_public(StatusCode::CREATED, &[], &[(CACHE_CONTROL, "public")], true)]
+    #[case::created_max_age(StatusCode::CREATED, &[], &[(CACHE_CONTROL, "max-age=60")], true)]
+    #[case::created_expires(
+        StatusCode::CREATED,
+        &[],
+        &[(EXPIRES, "Thu, 01 Dec 2044 16:00:00 GMT")],
+        true
+    )]
+    #[case::response_no_store(StatusCode::OK, &[], &[(CACHE_CONTROL, "no-store")], false)]
+    #[case::request_no_store(StatusCode::OK, &["no-store"], &[], false)]
+    #[case::vary(StatusCode::OK, &[], &[(VARY, "accept")], true)]
+    #[case::vary_any(StatusCode::OK, &[], &[(VARY, "*")], false)]
+    #[trace]
+    fn test_is_storable(
+        #[case] status: StatusCode,
+        #[case] request_cache_control: &[&str],
+        #[case] response_headers: &[(HeaderName, &str)],
+        #[case] expected: bool,
+    ) {
+        let mut request_headers = HeaderMap::new();
+        for value in request_cache_control {
+            request_headers.append(CACHE_CONTROL, value.parse().unwrap());
+        }
+        let request_cache_control = CacheControl::from_headers(&request_headers);
+        let mut headers = HeaderMap::new();
+        for (name, value) in response_headers {
+            headers.append(name, value.parse().unwrap());
+        }
+        assert_eq!(
+            is_storable(&request_cache_control, status, &headers),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::exact(Some("application/gzip"), true)]
+    #[case::parameters(Some("Application/GZip; charset=binary"), true)]
+    #[case::wildcard(Some("image/png"), true)]
+    #[case::not_allowed(Some("text/html; charset=utf-8"), false)]
+    #[case::wildcard_prefix(Some("imagex/png"), false)]
+    #[case::missing(None, false)]
+    #[trace]
+    fn test_is_allowed_content_type(#[case] content_type: Option<&str>, #[case] expected: bool) {
+        let allowed = ["application/gzip".to_string(), "image/*".to_string()];
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+        }
+        assert_eq!(is_allowed_content_type(&allowed, &headers), expected);
+    }
+}
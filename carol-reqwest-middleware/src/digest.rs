@@ -0,0 +1,108 @@
+//! Digests of response content from HTTP headers.
+//!
+//! Supported headers:
+//!
+//! - `Repr-Digest` and `Content-Digest` (RFC 9530), e.g. `sha-256=:<base64>:`;
+//! - `Digest` (RFC 3230), e.g. `SHA-256=<base64>`;
+//! - `x-checksum-sha256` (used by some artifact repositories), hex-encoded SHA-256;
+//! - `Content-MD5` (RFC 1864), base64-encoded MD5.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use carol::{Digest, DigestAlgorithm};
+use http::{HeaderMap, HeaderName};
+
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+const X_CHECKSUM_SHA256: HeaderName = HeaderName::from_static("x-checksum-sha256");
+const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+
+/// Get expected digest of the complete response content from `headers`.
+///
+/// If several digests are provided, the one with the strongest algorithm is returned. Values,
+/// which can't be parsed or use unsupported algorithms, are ignored.
+pub(crate) fn response_digest(headers: &HeaderMap) -> Option<Digest> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+    };
+    let mut digests = Vec::new();
+    for name in [REPR_DIGEST, CONTENT_DIGEST] {
+        digests.extend(values(name).filter_map(parse_structured));
+    }
+    digests.extend(values(DIGEST).filter_map(|value| value.parse().ok()));
+    digests.extend(values(X_CHECKSUM_SHA256).filter_map(|value| {
+        let value = hex::decode(value).ok()?;
+        Some(Digest::new(DigestAlgorithm::Sha256, value))
+    }));
+    digests.extend(values(CONTENT_MD5).filter_map(|value| {
+        let value = BASE64_STANDARD.decode(value).ok()?;
+        Some(Digest::new(DigestAlgorithm::Md5, value))
+    }));
+    // The first of the strongest digests
+    digests
+        .into_iter()
+        .rev()
+        .max_by_key(|digest| digest.algorithm)
+}
+
+/// Parse member of `Repr-Digest` or `Content-Digest` dictionary, e.g. `sha-256=:<base64>:`.
+fn parse_structured(member: &str) -> Option<Digest> {
+    let (algorithm, value) = member.split_once('=')?;
+    // Parameters of the member are ignored
+    let value = value.split(';').next()?.trim();
+    let value = value.strip_prefix(':')?.strip_suffix(':')?;
+    Some(Digest::new(
+        algorithm.trim().parse().ok()?,
+        BASE64_STANDARD.decode(value).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::response_digest;
+    use carol::{Digest, DigestAlgorithm};
+    use http::HeaderMap;
+    use rstest::rstest;
+
+    const SHA256: &str = "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+    const SHA256_HEX: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const SHA512: &str =
+        "MJ7MSJwS1utMxA9QyQLytNDtd+5RGnx6m808qG1M2G+YndNbxf9JlnDaNCVbRbDP2DDoH2Bdz33FVC6TrpzXbw==";
+    const MD5: &str = "XrY7u+Ae7tCTyyK7j1rNww==";
+
+    fn headers(values: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[rstest]
+    #[case::none(&[], None)]
+    #[case::repr_digest(&[("repr-digest", format!("sha-256=:{SHA256}:"))], Some(DigestAlgorithm::Sha256))]
+    #[case::content_digest(&[("content-digest", format!("sha-512=:{SHA512}:"))], Some(DigestAlgorithm::Sha512))]
+    #[case::digest(&[("digest", format!("SHA-256={SHA256}"))], Some(DigestAlgorithm::Sha256))]
+    #[case::x_checksum(&[("x-checksum-sha256", SHA256_HEX.to_string())], Some(DigestAlgorithm::Sha256))]
+    #[case::content_md5(&[("content-md5", MD5.to_string())], Some(DigestAlgorithm::Md5))]
+    #[case::strongest(
+        &[("content-md5", MD5.to_string()), ("repr-digest", format!("sha-256=:{SHA256}:, sha-512=:{SHA512}:"))],
+        Some(DigestAlgorithm::Sha512),
+    )]
+    #[case::unknown_skipped(&[("digest", format!("UNIXsum=30637, MD5={MD5}"))], Some(DigestAlgorithm::Md5))]
+    #[case::invalid(&[("repr-digest", format!("sha-256={SHA256}")), ("x-checksum-sha256", "xyz".to_string())], None)]
+    #[trace]
+    fn test_response_digest(
+        #[case] values: &[(&'static str, String)],
+        #[case] expected: Option<DigestAlgorithm>,
+    ) {
+        let digest = response_digest(&headers(values));
+        let expected = expected.map(|algorithm| Digest::compute(algorithm, "hello world"));
+        assert_eq!(digest, expected);
+    }
+}
@@ -0,0 +1,43 @@
+use carol::{StorageDatabaseError, StorageError};
+
+/// Carol middleware error.
+///
+/// Converts into [`reqwest_middleware::Error::Middleware`], so the original error can be
+/// retrieved with `anyhow::Error::downcast_ref`.
+#[derive(thiserror::Error, Debug)]
+pub enum CarolMiddlewareError<E: StorageDatabaseError> {
+    /// Storage operation failed.
+    #[error("storage error")]
+    StorageError(#[from] StorageError<E>),
+
+    /// Failed to read stored file.
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+
+    /// Failed to serialize stored file into response body.
+    #[error("failed to serialize stored file")]
+    SerializeError(#[from] serde_json::Error),
+
+    /// Origin responded with a range, which does not continue partially stored file.
+    #[error("unexpected content range")]
+    UnexpectedContentRange,
+
+    /// Response without declared length exceeded maximum size while being stored.
+    ///
+    /// Returned in [`BodyMode::Metadata`](crate::BodyMode::Metadata), because the content is
+    /// consumed by storage.
+    #[error("response exceeds maximum size of {max_size} bytes")]
+    ResponseTooLarge { max_size: u64 },
+
+    /// Failed to build response.
+    #[error("failed to build response")]
+    ResponseBuildError(#[from] http::Error),
+}
+
+impl<E: StorageDatabaseError + 'static> From<CarolMiddlewareError<E>>
+    for reqwest_middleware::Error
+{
+    fn from(err: CarolMiddlewareError<E>) -> Self {
+        reqwest_middleware::Error::middleware(err)
+    }
+}
@@ -8,12 +8,12 @@
 //! use carol_reqwest_middleware::CarolMiddleware;
 //!
 //! let storage_manager = StorageManager::init(database_url, cache_dir, None).await.unwrap();
-//! let carol_middleware = CarolMiddleware {
+//! let carol_middleware = CarolMiddleware::new(
 //!     storage_manager,
-//!     store_policy: StorePolicy::ExpiresAfterNotUsedFor {
+//!     StorePolicy::ExpiresAfterNotUsedFor {
 //!         duration: std::time::Duration::from_secs(3600),
 //!     },
-//! };
+//! );
 //!
 //! let reqwest_client = reqwest::Client::builder().build().unwrap();
 //! let client = reqwest_middleware::ClientBuilder::new(reqwest_client).with(carol_middleware).build();
@@ -25,25 +25,305 @@
 //! # }
 //! ```
 
+mod body;
+mod cache_control;
+mod cache_key;
+mod cacheable;
+mod digest;
+mod error;
+mod mirrors;
+mod resume;
+
+use std::future::Future;
+use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use carol::chrono::{DateTime, TimeDelta, Utc};
 use content_disposition::parse_content_disposition;
-use http::header::CONTENT_DISPOSITION;
-use http::Extensions;
-use reqwest_middleware::reqwest::{Body, Request, Response, ResponseBuilderExt};
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
+use http::header::{
+    AGE, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, LAST_MODIFIED,
+    RANGE, WARNING,
+};
+use http::{Extensions, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use reqwest_middleware::reqwest::{self, Body, Request, Response, ResponseBuilderExt, Url};
 use reqwest_middleware::{Middleware, Next};
 use serde::Serialize;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
 
 #[doc(no_inline)]
 pub use carol as storage;
 
+/// Value of `Warning` header of responses served from storage because the origin failed.
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+pub use body::BodyMode;
+pub use cache_key::CacheKeyFn;
+pub use error::CarolMiddlewareError;
+pub use mirrors::Mirrors;
+
 use carol::sqlite::SqliteStorageDatabase;
-use carol::{StorageDatabaseExt, StorageManager, StorePolicy};
+use carol::{
+    Digest, File, FileSource, FileStatus, StorageDatabaseError, StorageDatabaseExt, StorageError,
+    StorageManager, StorePolicy,
+};
+
+use body::{body_from_channel, exceeds_size_limit, limit_size, tee, BodyChunk};
+use cache_control::CacheControl;
+use cache_key::{
+    default_cache_key, fold_headers, merge_headers, vary_headers, VaryCache, DEFAULT_VARY_CAPACITY,
+};
+use cacheable::{is_allowed_content_type, is_cacheable_method, is_negative_status, is_storable};
+use digest::response_digest;
+use resume::{content_range_start, range_validator};
 
+/// Caching middleware.
+///
+/// Only `GET` and `HEAD` requests are handled, other requests are passed through untouched.
+///
+/// Successful response to `GET` request is stored if it is allowed by the rules of RFC 9111,
+/// and the response body is replaced according to [`BodyMode`] (JSON-serialized [`File`] by
+/// default). If the response can't be stored, it is returned untouched. Fresh stored files are
+/// served without contacting the origin. `HEAD` requests are answered from stored file metadata
+/// when possible.
+///
+/// # Cache keys
+///
+/// Responses are stored under the request URL by default. Use [`Self::with_cache_key`] to compute
+/// the key differently and [`Self::with_key_headers`] to keep separate responses for different
+/// values of request headers (e.g. `Accept` or `Authorization`). Headers listed in `Vary`
+/// response header are taken into account automatically. Header values are folded into the
+/// key as a SHA256 suffix, so they are never stored in plain text.
+///
+/// Requests with [`Mirrors`] extension are stored under the canonical source of the mirrors.
+///
+/// Headers listed in `Vary` are remembered in memory, so the first request for a URL from a new
+/// middleware instance may reach the origin even if a matching response is stored. Only a
+/// limited number of URLs is remembered (see [`Self::with_vary_capacity`]); least recently used
+/// ones are forgotten first.
+///
+/// # Resumable downloads
+///
+/// With [`Self::with_resumable_downloads`] interrupted downloads are not discarded, if the origin
+/// supports byte ranges. Such files are kept partially stored, and the download is continued
+/// with `Range` request (validated by `If-Range`) on the next attempt.
+///
+/// In [`BodyMode::Transparent`] the response is returned before the download completes, so
+/// interrupted downloads are not retried within the request and the error is yielded by the
+/// response body. They are resumed on the next request.
+///
+/// # Stale-if-error
+///
+/// With [`Self::with_stale_if_error`] (or `stale-if-error` request `Cache-Control` directive,
+/// RFC 5861) expired stored files are served if the origin is unreachable or responds with
+/// server error (5xx). Such responses have `Warning: 110 - "Response is Stale"` header.
+///
+/// # Negative caching
+///
+/// With [`Self::with_negative_caching`] `404 Not Found` and `410 Gone` responses are recorded in
+/// storage as files without content. Until they expire, requests are answered with the recorded
+/// status without contacting the origin.
+///
+/// # Integrity verification
+///
+/// If the origin provides a digest of the response content in `Repr-Digest`, `Content-Digest`,
+/// `Digest`, `x-checksum-sha256` or `Content-MD5` header, the downloaded content is verified
+/// against it before the stored file becomes ready. Mismatching file is removed and the request
+/// fails with [`StorageError::DigestMismatch`]. The digest is recorded in
+/// [`FileMetadata::digest`](carol::FileMetadata::digest). Verification can be turned off with
+/// [`Self::with_digest_verification`].
+///
+/// # Size and content type filters
+///
+/// Responses larger than [`Self::with_max_size`] or with content type not allowed by
+/// [`Self::with_content_types`] are returned untouched without storing. Responses without
+/// `Content-Length` are stored until they exceed the maximum size, then storing stops. In
+/// [`BodyMode::Transparent`] the body is still returned untouched, in [`BodyMode::Metadata`]
+/// the request fails with [`CarolMiddlewareError::ResponseTooLarge`]. Such responses can be
+/// skipped upfront with [`Self::with_content_length_required`].
 pub struct CarolMiddleware<D: StorageDatabaseExt = SqliteStorageDatabase> {
     pub storage_manager: StorageManager<D>,
     pub store_policy: StorePolicy,
+    cache_key: Arc<CacheKeyFn>,
+    key_headers: Vec<HeaderName>,
+    vary: Mutex<VaryCache>,
+    resume_attempts: Option<usize>,
+    body_mode: BodyMode,
+    stale_if_error: Option<Duration>,
+    negative_ttl: Option<Duration>,
+    verify_digests: bool,
+    max_size: Option<u64>,
+    content_length_required: bool,
+    content_types: Option<Vec<String>>,
+}
+
+impl<D: StorageDatabaseExt> CarolMiddleware<D> {
+    /// Create middleware storing files in `storage_manager` with given `store_policy`.
+    ///
+    /// Other options have defaults and can be changed with `with_*` methods. This replaces
+    /// constructing the middleware with a struct literal, `CarolMiddleware { storage_manager,
+    /// store_policy }`, which is no longer possible.
+    pub fn new(storage_manager: StorageManager<D>, store_policy: StorePolicy) -> Self {
+        Self {
+            storage_manager,
+            store_policy,
+            cache_key: Arc::new(default_cache_key),
+            key_headers: Vec::new(),
+            vary: Mutex::new(VaryCache::new(DEFAULT_VARY_CAPACITY)),
+            resume_attempts: None,
+            body_mode: BodyMode::default(),
+            stale_if_error: None,
+            negative_ttl: None,
+            verify_digests: true,
+            max_size: None,
+            content_length_required: false,
+            content_types: None,
+        }
+    }
+
+    /// Use custom function to compute the base cache key of a request.
+    ///
+    /// Request headers selected with [`Self::with_key_headers`] and `Vary` response header are
+    /// still folded into the key returned by `cache_key`.
+    pub fn with_cache_key(
+        mut self,
+        cache_key: impl Fn(&Request) -> FileSource + Send + Sync + 'static,
+    ) -> Self {
+        self.cache_key = Arc::new(cache_key);
+        self
+    }
+
+    /// Fold values of request `headers` into cache keys.
+    pub fn with_key_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        let headers: Vec<_> = headers.into_iter().collect();
+        self.key_headers = merge_headers([headers.as_slice()]);
+        self
+    }
+
+    /// Remember `Vary` headers for at most `capacity` base cache keys. Defaults to 1024.
+    pub fn with_vary_capacity(mut self, capacity: usize) -> Self {
+        self.vary = Mutex::new(VaryCache::new(capacity));
+        self
+    }
+
+    /// Serve stored files up to `window` after their expiration if the origin fails.
+    ///
+    /// `stale-if-error` directive of request `Cache-Control` header overrides the window.
+    pub fn with_stale_if_error(mut self, window: Duration) -> Self {
+        self.stale_if_error = Some(window);
+        self
+    }
+
+    /// Record `404 Not Found` and `410 Gone` responses to `GET` requests for `ttl`.
+    ///
+    /// Requests for the same resource are answered with the recorded status until it expires.
+    pub fn with_negative_caching(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// Keep interrupted downloads and resume them with `Range` requests.
+    ///
+    /// Interrupted download is retried up to `max_attempts` times within a single request.
+    /// If it still fails, partially stored file is resumed on the next request.
+    pub fn with_resumable_downloads(mut self, max_attempts: usize) -> Self {
+        self.resume_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Verify stored content against digests provided by the origin. Enabled by default.
+    pub fn with_digest_verification(mut self, enabled: bool) -> Self {
+        self.verify_digests = enabled;
+        self
+    }
+
+    /// Don't store responses larger than `max_size` bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Don't store responses without `Content-Length` header.
+    pub fn with_content_length_required(mut self, required: bool) -> Self {
+        self.content_length_required = required;
+        self
+    }
+
+    /// Store only responses with one of the content `types`, e.g. `application/gzip`.
+    ///
+    /// Types may use `*` subtype wildcard, e.g. `image/*`. Parameters of `Content-Type` header
+    /// are ignored. Responses without `Content-Type` are not stored.
+    pub fn with_content_types<T: Into<String>>(
+        mut self,
+        types: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.content_types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set body of responses to `GET` requests.
+    pub fn with_body_mode(mut self, body_mode: BodyMode) -> Self {
+        self.body_mode = body_mode;
+        self
+    }
+
+    /// Check if `file` may be served, because the origin failed.
+    ///
+    /// The file may be served if it is expired no longer than the stale-if-error window ago.
+    fn may_serve_stale(&self, file: &File, request_cache_control: &CacheControl) -> bool {
+        let window = request_cache_control
+            .seconds("stale-if-error")
+            .map(Duration::from_secs)
+            .or(self.stale_if_error);
+        let Some(window) = window else {
+            return false;
+        };
+        let staleness = match file.metadata.time_to_live(Utc::now()) {
+            Some(time_to_live) => -time_to_live,
+            None => TimeDelta::zero(),
+        };
+        staleness <= TimeDelta::from_std(window).unwrap_or(TimeDelta::MAX)
+    }
+
+    /// Check if the response passes size and content type filters.
+    ///
+    /// Responses without declared length are accepted, unless it is required.
+    fn passes_filters(&self, headers: &HeaderMap, content_length: Option<u64>) -> bool {
+        let size_allowed = match (content_length, self.max_size) {
+            (Some(len), Some(max_size)) => len <= max_size,
+            (Some(_), None) => true,
+            (None, _) => !self.content_length_required,
+        };
+        let type_allowed = match &self.content_types {
+            Some(types) => is_allowed_content_type(types, headers),
+            None => true,
+        };
+        size_allowed && type_allowed
+    }
+
+    /// Compute cache key of the request with given `base` key.
+    ///
+    /// Configured key headers and headers previously seen in `Vary` for this `base` are folded
+    /// into the key.
+    fn cache_key(&self, base: &FileSource, request_headers: &HeaderMap) -> FileSource {
+        let mut vary = self.vary.lock().unwrap();
+        let vary_headers = vary.get(base).unwrap_or_default();
+        let headers = merge_headers([self.key_headers.as_slice(), vary_headers]);
+        fold_headers(base.clone(), &headers, request_headers)
+    }
+
+    /// Remember headers from `Vary` header of the response for requests with `base` key.
+    fn remember_vary(&self, base: &FileSource, vary_headers: Vec<HeaderName>) {
+        self.vary.lock().unwrap().insert(base, vary_headers);
+    }
 }
 
 #[async_trait]
@@ -51,6 +331,7 @@ impl<D> Middleware for CarolMiddleware<D>
 where
     D: StorageDatabaseExt + 'static,
     D::Uri: Serialize + Send,
+    D::Error: 'static,
 {
     async fn handle(
         &self,
@@ -58,33 +339,661 @@ where
         extensions: &mut Extensions,
         next: Next<'_>,
     ) -> reqwest_middleware::Result<Response> {
-        let mut builder = http::Response::builder();
+        if !is_cacheable_method(req.method()) {
+            return next.run(req, extensions).await;
+        }
+        let request_cache_control = CacheControl::from_headers(req.headers());
+        if request_cache_control.has("no-store") {
+            return next.run(req, extensions).await;
+        }
+
         let url = req.url().to_owned();
-        builder = builder.url(url.clone());
+        // Files fetched from mirrors are stored under the canonical source
+        let mirrors = extensions.get::<Mirrors>().cloned();
+        let base_key = match &mirrors {
+            Some(mirrors) => mirrors.source.clone(),
+            None => (self.cache_key)(&req),
+        };
+        let request_headers = req.headers().clone();
+        let revalidate = request_cache_control.has("no-cache");
 
-        let origin_response = next.run(req, extensions).await?.error_for_status()?;
+        let source = self.cache_key(&base_key, &request_headers);
+        let stored = self.storage_manager.find_by_source(&source).await;
+        let stored = stored.map_err(CarolMiddlewareError::from)?;
+        match &stored {
+            // Stored file is fresh unless it's expired or the client asks for revalidation
+            Some(file)
+                if file.status == FileStatus::Ready
+                    && !file.metadata.is_expired(Utc::now())
+                    && !revalidate =>
+            {
+                // Only responses with content count as uses of the file
+                if req.method() == Method::HEAD {
+                    return self.stored_response(req.method(), url, file).await;
+                }
+                let file = self.storage_manager.use_file(file.id).await;
+                let file = file.map_err(CarolMiddlewareError::from)?;
+                return self.stored_response(req.method(), url, &file).await;
+            }
+            // File being stored by concurrent request is read while it grows
+            Some(file)
+                if file.status == FileStatus::Pending
+                    && self.body_mode == BodyMode::Transparent
+                    && req.method() == Method::GET =>
+            {
+                return Ok(self.growing_response(url, file)?);
+            }
+            _ => {}
+        }
 
-        builder = builder.status(origin_response.status());
-        builder = builder.version(origin_response.version());
-        for header in origin_response.headers() {
-            builder = builder.header(header.0, header.1);
+        // Nothing to store from the response to HEAD request
+        if req.method() == Method::HEAD {
+            return next.run(req, extensions).await;
         }
 
+        let candidates = match &mirrors {
+            Some(mirrors) => mirrors.candidates(&url),
+            None => vec![url.clone()],
+        };
+        let template = match candidates.len() {
+            1 => None,
+            _ => req.try_clone(),
+        };
+        let mut request = Some(req);
+        let mut result = None;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let request = match request.take() {
+                Some(request) => request,
+                // Mirrors are tried only if the request can be repeated
+                None => match template.as_ref().and_then(Request::try_clone) {
+                    Some(mut request) => {
+                        *request.url_mut() = candidate.clone();
+                        request
+                    }
+                    None => break,
+                },
+            };
+            let fetch = Fetch {
+                base_key: &base_key,
+                cache_control: &request_cache_control,
+                revalidate,
+                // Negative result of a mirror doesn't mean that the resource doesn't exist
+                record_negative: i + 1 == candidates.len(),
+            };
+            let fetched = self
+                .fetch_with_retries(request, extensions, next.clone(), &fetch)
+                .await;
+            // Storage failures are not specific to a mirror, so other mirrors are not tried
+            let done = match &fetched {
+                Ok(_) => true,
+                Err(err) => is_local_failure::<D::Error>(err),
+            };
+            result = Some(fetched);
+            if done {
+                break;
+            }
+        }
+        let result = result.expect("request URL is always a candidate");
+
+        match (result, stored) {
+            (Err(err), Some(file))
+                if file.status == FileStatus::Ready
+                    && is_origin_failure(&err)
+                    && self.may_serve_stale(&file, &request_cache_control) =>
+            {
+                // Stored file may be already replaced if the failure occurred while storing
+                match self.find_ready(&source).await? {
+                    Some(stale) if stale.id == file.id => self.stale_response(url, &stale).await,
+                    _ => Err(err),
+                }
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+impl<D> CarolMiddleware<D>
+where
+    D: StorageDatabaseExt + 'static,
+    D::Uri: Serialize + Send,
+    D::Error: 'static,
+{
+    /// Fetch response from origin and store it, resuming interrupted download if configured.
+    async fn fetch_with_retries(
+        &self,
+        mut request: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+        fetch: &Fetch<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let mut attempts_left = self.resume_attempts.unwrap_or(0);
+        loop {
+            // Download is retried only if the request can be repeated
+            let retry = match attempts_left {
+                0 => None,
+                _ => request.try_clone(),
+            };
+            let request_headers = request.headers().clone();
+            let result = self.fetch(request, extensions, next.clone(), fetch).await;
+            match (result, retry) {
+                (Err(err), Some(retry)) => {
+                    // Nothing to resume if the download failed before anything was stored
+                    let source = self.cache_key(fetch.base_key, &request_headers);
+                    if self.find_partial(&source).await?.is_none() {
+                        return Err(err);
+                    }
+                    request = retry;
+                    attempts_left -= 1;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    /// Fetch response from origin and store it.
+    ///
+    /// If there is a partially stored file, the request is made for the rest of its content.
+    async fn fetch(
+        &self,
+        mut request: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+        fetch: &Fetch<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let Fetch {
+            base_key,
+            cache_control: request_cache_control,
+            revalidate,
+            record_negative,
+        } = *fetch;
+        let url = request.url().to_owned();
+        let request_headers = request.headers().clone();
+
+        let partial = match self.resume_attempts {
+            Some(_) => {
+                let source = self.cache_key(base_key, &request_headers);
+                self.find_partial(&source).await?
+            }
+            None => None,
+        };
+        if let Some((file, offset)) = &partial {
+            let range =
+                HeaderValue::try_from(format!("bytes={}-", offset)).map_err(http::Error::from);
+            let range = range.map_err(CarolMiddlewareError::<D::Error>::from)?;
+            let headers = request.headers_mut();
+            headers.insert(RANGE, range);
+            if let Some(validator) = &file.metadata.validator {
+                if let Ok(validator) = HeaderValue::from_str(validator) {
+                    headers.insert(IF_RANGE, validator);
+                }
+            }
+        }
+
+        let origin_response = next.run(request, extensions).await?;
+        if let Some(ttl) = self.negative_ttl.filter(|_| record_negative) {
+            let status = origin_response.status();
+            if is_negative_status(status)
+                && is_storable(request_cache_control, status, origin_response.headers())
+            {
+                self.remember_vary(
+                    base_key,
+                    vary_headers(origin_response.headers()).unwrap_or_default(),
+                );
+                let source = self.cache_key(base_key, &request_headers);
+                self.store_negative(source, revalidate, ttl, status).await?;
+            }
+        }
+        let origin_response = origin_response.error_for_status()?;
+
+        if let Some((file, offset)) = partial {
+            if origin_response.status() == StatusCode::PARTIAL_CONTENT {
+                if content_range_start(origin_response.headers()) != Some(offset) {
+                    return Err(CarolMiddlewareError::<D::Error>::UnexpectedContentRange.into());
+                }
+                let mut builder = response_builder(url, &origin_response)
+                    .status(StatusCode::OK)
+                    .version(origin_response.version());
+                if let Some(len) = origin_response.content_length() {
+                    builder = builder.header(CONTENT_LENGTH, offset + len);
+                }
+                let stream = origin_response.bytes_stream();
+                return Ok(self.resume(file, offset, builder, stream).await?);
+            }
+            // Otherwise origin ignored the range and sent full content
+        }
+
+        if !is_storable(
+            request_cache_control,
+            origin_response.status(),
+            origin_response.headers(),
+        ) || !self.passes_filters(origin_response.headers(), origin_response.content_length())
+        {
+            return Ok(origin_response);
+        }
+
+        // Storable response has no `Vary: *`
+        self.remember_vary(
+            base_key,
+            vary_headers(origin_response.headers()).unwrap_or_default(),
+        );
+        let source = self.cache_key(base_key, &request_headers);
+
+        let builder = response_builder(url, &origin_response)
+            .status(origin_response.status())
+            .version(origin_response.version());
+
+        Ok(self
+            .store(source, revalidate, builder, origin_response)
+            .await?)
+    }
+
+    /// Find file, which is ready to be used, by its source.
+    async fn find_ready(
+        &self,
+        source: &FileSource,
+    ) -> Result<Option<File>, CarolMiddlewareError<D::Error>> {
+        let file = self.storage_manager.find_by_source(source).await?;
+        Ok(file.filter(|file| file.status == FileStatus::Ready))
+    }
+
+    /// Find partially stored file by its source.
+    ///
+    /// Returns the file with the size of its stored content.
+    async fn find_partial(
+        &self,
+        source: &FileSource,
+    ) -> Result<Option<(File, u64)>, CarolMiddlewareError<D::Error>> {
+        let Some(file) = self.storage_manager.find_by_source(source).await? else {
+            return Ok(None);
+        };
+        if file.status != FileStatus::Partial {
+            return Ok(None);
+        }
+        let size = fs::metadata(&file.metadata.path).await?.len();
+        Ok(Some((file, size)))
+    }
+
+    /// Build response for a file found in storage.
+    ///
+    /// Response to `HEAD` request has no body and reports size of the stored file
+    /// in `Content-Length` header.
+    async fn cached_response(
+        &self,
+        method: &Method,
+        url: Url,
+        file: &File,
+    ) -> Result<Response, CarolMiddlewareError<D::Error>> {
+        let age = (Utc::now() - file.metadata.created).num_seconds().max(0);
+        let builder = http::Response::builder()
+            .url(url)
+            .status(StatusCode::OK)
+            .header(AGE, age)
+            .header(LAST_MODIFIED, http_date(file.metadata.created));
+        let response = if method == Method::HEAD {
+            let size = fs::metadata(&file.metadata.path).await?.len();
+            builder
+                .header(CONTENT_LENGTH, size)
+                .body(Body::from(Vec::new()))?
+        } else if self.body_mode == BodyMode::Transparent {
+            let size = fs::metadata(&file.metadata.path).await?.len();
+            let content = self.storage_manager.read_file_stream(file);
+            builder
+                .header(CONTENT_LENGTH, size)
+                .body(Body::wrap_stream(content))?
+        } else {
+            builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(file)?))?
+        };
+        Ok(Response::from(response))
+    }
+
+    /// Build response for stored `file` served in place of failed origin response.
+    ///
+    /// The response is marked with `Warning: 110` header.
+    async fn stale_response(&self, url: Url, file: &File) -> reqwest_middleware::Result<Response> {
+        let mut response = self.stored_response(&Method::GET, url, file).await?;
+        response
+            .headers_mut()
+            .insert(WARNING, HeaderValue::from_static(STALE_WARNING));
+        Ok(response)
+    }
+
+    /// Build response for a file found in storage.
+    ///
+    /// Negative results are replayed with their status code. Like responses from the origin,
+    /// response to `GET` request with error status is turned into error.
+    async fn stored_response(
+        &self,
+        method: &Method,
+        url: Url,
+        file: &File,
+    ) -> reqwest_middleware::Result<Response> {
+        let Some(status) = file.metadata.negative_status else {
+            return Ok(self.cached_response(method, url, file).await?);
+        };
+        let status = StatusCode::from_u16(status).map_err(http::Error::from);
+        let status = status.map_err(CarolMiddlewareError::<D::Error>::from)?;
+        let response = http::Response::builder()
+            .url(url)
+            .status(status)
+            .body(Body::from(Vec::new()));
+        let response = Response::from(response.map_err(CarolMiddlewareError::<D::Error>::from)?);
+        if method == Method::HEAD {
+            return Ok(response);
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    /// Build response for a file, which is being stored by concurrent request.
+    ///
+    /// Response body yields content of the file as it is written.
+    fn growing_response(
+        &self,
+        url: Url,
+        file: &File,
+    ) -> Result<Response, CarolMiddlewareError<D::Error>> {
+        let content = self.storage_manager.read_file_stream(file);
+        let response = http::Response::builder()
+            .url(url)
+            .status(StatusCode::OK)
+            .body(Body::wrap_stream(content))?;
+        Ok(Response::from(response))
+    }
+
+    /// Remove file stored with `source` if it's expired or `revalidate` is requested.
+    async fn remove_outdated(
+        &self,
+        source: &FileSource,
+        revalidate: bool,
+    ) -> Result<(), CarolMiddlewareError<D::Error>> {
+        if let Some(file) = self.find_ready(source).await? {
+            if revalidate || file.metadata.is_expired(Utc::now()) {
+                self.storage_manager.remove_file(file.id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record negative result with `status` for `source`, which expires after `ttl`.
+    ///
+    /// File stored with the same `source` is removed first if it's outdated.
+    async fn store_negative(
+        &self,
+        source: FileSource,
+        revalidate: bool,
+        ttl: Duration,
+        status: StatusCode,
+    ) -> Result<(), CarolMiddlewareError<D::Error>> {
+        self.remove_outdated(&source, revalidate).await?;
+        let store_policy = StorePolicy::ExpiresAfter { duration: ttl };
+        self.storage_manager
+            .add_negative_file(source, store_policy, status.as_u16())
+            .await?;
+        Ok(())
+    }
+
+    /// Store origin response body in storage and build response with stored file as a body.
+    ///
+    /// File stored with the same `source` is removed first if it's outdated.
+    async fn store(
+        &self,
+        source: FileSource,
+        revalidate: bool,
+        builder: http::response::Builder,
+        origin_response: Response,
+    ) -> Result<Response, CarolMiddlewareError<D::Error>> {
+        self.remove_outdated(&source, revalidate).await?;
+
         let filename = get_filename(&origin_response);
+        let content_length = origin_response.content_length();
+        let validator = match self.resume_attempts {
+            // Response exceeding maximum size while being stored must not be kept partial
+            Some(_) if content_length.is_some() || self.max_size.is_none() => {
+                range_validator(origin_response.headers())
+            }
+            _ => None,
+        };
+        let digest = response_digest(origin_response.headers()).filter(|_| self.verify_digests);
         let stream = origin_response.bytes_stream();
+        let max_size = self.max_size;
 
-        let file = self
-            .storage_manager
-            .add_file_from_stream(url.into(), self.store_policy, filename, stream)
-            .await
-            .unwrap();
+        match self.body_mode {
+            BodyMode::Metadata => {
+                let file = add_file(
+                    &self.storage_manager,
+                    self.store_policy,
+                    source,
+                    filename,
+                    validator,
+                    digest,
+                    limit_size(stream, max_size),
+                )
+                .await
+                .map_err(|err| match max_size {
+                    Some(max_size) if exceeds_size_limit(&err) => {
+                        CarolMiddlewareError::ResponseTooLarge { max_size }
+                    }
+                    _ => err.into(),
+                })?;
+                let body = Body::from(serde_json::to_string(&file)?);
+                Ok(Response::from(builder.body(body)?))
+            }
+            BodyMode::Transparent => {
+                let storage_manager = self.storage_manager.clone();
+                let store_policy = self.store_policy;
+                let body = self.tee_body(
+                    futures_util::stream::empty(),
+                    stream,
+                    max_size,
+                    move |stream| async move {
+                        add_file(
+                            &storage_manager,
+                            store_policy,
+                            source,
+                            filename,
+                            validator,
+                            digest,
+                            stream,
+                        )
+                        .await
+                    },
+                );
+                let builder = match content_length {
+                    Some(len) => builder.header(CONTENT_LENGTH, len),
+                    None => builder,
+                };
+                Ok(Response::from(builder.body(body)?))
+            }
+        }
+    }
 
-        let body = Body::from(serde_json::to_string(&file).unwrap());
-        let response = Response::from(builder.body(body).unwrap());
-        Ok(response)
+    /// Append the rest of partially stored file from `stream`, which starts at `offset`, and
+    /// build response with body according to the body mode.
+    async fn resume<S>(
+        &self,
+        file: File,
+        offset: u64,
+        builder: http::response::Builder,
+        stream: S,
+    ) -> Result<Response, CarolMiddlewareError<D::Error>>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+    {
+        match self.body_mode {
+            BodyMode::Metadata => {
+                let file = self
+                    .storage_manager
+                    .resume_file_from_stream(file.id, stream)
+                    .await?;
+                let body = Body::from(serde_json::to_string(&file)?);
+                Ok(Response::from(builder.body(body)?))
+            }
+            BodyMode::Transparent => {
+                // Stored part is read before the rest is appended to it
+                let input = fs::File::open(&file.metadata.path).await?.take(offset);
+                let storage_manager = self.storage_manager.clone();
+                let body = self.tee_body(
+                    ReaderStream::new(input),
+                    stream,
+                    None,
+                    move |stream| async move {
+                        storage_manager
+                            .resume_file_from_stream(file.id, stream)
+                            .await
+                    },
+                );
+                Ok(Response::from(builder.body(body)?))
+            }
+        }
+    }
+
+    /// Store content of `origin` stream in background with `store` and create body, which
+    /// yields `prefix` followed by the content as it is stored.
+    ///
+    /// Storage errors are yielded by the body. If `store` does not consume the stream, because
+    /// the file is stored by concurrent request, the body yields content of the stored file.
+    /// If the content exceeds `max_size`, storing stops and the body yields the rest of `origin`.
+    fn tee_body<S, F, Fut>(
+        &self,
+        prefix: impl Stream<Item = BodyChunk> + Send + 'static,
+        origin: S,
+        max_size: Option<u64>,
+        store: F,
+    ) -> Body
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+        F: FnOnce(BoxStream<'static, BodyChunk>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<File, StorageError<D::Error>>> + Send + 'static,
+    {
+        let consumed = Arc::new(AtomicBool::new(false));
+        let (stream, sender, receiver) = tee(origin, max_size, consumed.clone());
+        let storage_manager = self.storage_manager.clone();
+        tokio::spawn(async move {
+            match store(stream).await {
+                Ok(file) if !consumed.load(Ordering::Acquire) => {
+                    let mut content = Box::pin(storage_manager.read_file_stream(&file));
+                    while let Some(chunk) = content.next().await {
+                        if sender.send(chunk.map_err(io::Error::other)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Ok(_) => {}
+                // The rest of the content is passed to the body by the tee
+                Err(err) if exceeds_size_limit(&err) => {}
+                Err(err) => {
+                    let _ = sender.send(Err(io::Error::other(err))).await;
+                }
+            }
+        });
+        body_from_channel(prefix, receiver)
+    }
+}
+
+/// Add file to storage, keeping it resumable if the `validator` is known and verifying it if the
+/// `digest` is known.
+async fn add_file<D, S>(
+    storage_manager: &StorageManager<D>,
+    store_policy: StorePolicy,
+    source: FileSource,
+    filename: Option<String>,
+    validator: Option<String>,
+    digest: Option<Digest>,
+    stream: S,
+) -> Result<File, StorageError<D::Error>>
+where
+    D: StorageDatabaseExt,
+    S: Stream<Item = BodyChunk> + Unpin,
+{
+    match (validator, digest) {
+        (Some(validator), digest) => {
+            storage_manager
+                .add_resumable_file_from_stream(
+                    source,
+                    store_policy,
+                    filename,
+                    Some(validator),
+                    digest,
+                    stream,
+                )
+                .await
+        }
+        (None, Some(digest)) => {
+            storage_manager
+                .add_verified_file_from_stream(source, store_policy, filename, digest, stream)
+                .await
+        }
+        (None, None) => {
+            storage_manager
+                .add_file_from_stream(source, store_policy, filename, stream)
+                .await
+        }
+    }
+}
+
+/// Parameters of fetching a response to store.
+#[derive(Clone, Copy)]
+struct Fetch<'a> {
+    /// Base cache key of the request.
+    base_key: &'a FileSource,
+
+    /// `Cache-Control` directives of the request.
+    cache_control: &'a CacheControl,
+
+    /// Stored file must be replaced.
+    revalidate: bool,
+
+    /// Negative result may be recorded.
+    record_negative: bool,
+}
+
+/// Check if the request failed because the origin is unreachable or responded with server error.
+///
+/// Errors of the middleware itself (e.g. storage errors) are not origin failures.
+fn is_origin_failure(err: &reqwest_middleware::Error) -> bool {
+    match err {
+        reqwest_middleware::Error::Reqwest(err) => match err.status() {
+            Some(status) => status.is_server_error(),
+            None => err.is_connect() || err.is_timeout() || err.is_request(),
+        },
+        reqwest_middleware::Error::Middleware(_) => false,
     }
 }
 
+/// Check if the request failed locally, i.e. storage or file system failed.
+///
+/// Failures to read the response body while storing it are not local.
+fn is_local_failure<E: StorageDatabaseError + 'static>(err: &reqwest_middleware::Error) -> bool {
+    let reqwest_middleware::Error::Middleware(err) = err else {
+        return false;
+    };
+    match err.downcast_ref::<CarolMiddlewareError<E>>() {
+        Some(CarolMiddlewareError::StorageError(StorageError::CustomError(_))) => false,
+        Some(CarolMiddlewareError::StorageError(_) | CarolMiddlewareError::IoError(_)) => true,
+        _ => false,
+    }
+}
+
+/// Create response builder with URL and headers of the origin response.
+///
+/// `Content-Range` and `Content-Length` headers are skipped, because the body is replaced.
+fn response_builder(url: Url, origin_response: &Response) -> http::response::Builder {
+    let mut builder = http::Response::builder();
+    builder = builder.url(url);
+    for (name, value) in origin_response.headers() {
+        if name != CONTENT_RANGE && name != CONTENT_LENGTH {
+            builder = builder.header(name, value);
+        }
+    }
+    builder
+}
+
+/// Format timestamp as HTTP date (RFC 9110, section 5.6.7).
+fn http_date(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
 /// Try getting file name from HTTP response.
 fn get_filename(response: &Response) -> Option<String> {
     // Try getting file name from Content-Disposition first
@@ -102,16 +1011,32 @@ fn get_filename(response: &Response) -> Option<String> {
         .map(ToOwned::to_owned)
 }
 
-// FIXME: remove unwrap()
-
 #[cfg(test)]
 mod tests {
-    use super::CarolMiddleware;
-    use carol::{File, StorageManager, StorePolicy};
-    use http_test_server::TestServer;
+    use super::{BodyMode, CarolMiddleware, CarolMiddlewareError, Mirrors, STALE_WARNING};
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use carol::sqlite::error::DatabaseError;
+    use carol::{
+        Digest, DigestAlgorithm, File, FileSource, FileStatus, StorageError, StorageManager,
+        StorePolicy,
+    };
+    use http::header::{
+        ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, WARNING,
+    };
+    use http::StatusCode;
+    use http_test_server::http::{Method, Status};
+    use http_test_server::{Resource, TestServer};
+    use reqwest_middleware::ClientWithMiddleware;
     use rstest::{fixture, rstest};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
+    use tempfile::TempDir;
     use tokio::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Notify;
 
     const DEFAULT_PATH: &str = "/hello.txt";
     const DEFAULT_CONTENT: &str = "Hello world";
@@ -125,7 +1050,6 @@ mod tests {
         #[default(DEFAULT_CONTENT)] content: &'static str,
         #[default(DEFAULT_HEADERS)] headers: &[(&'static str, &'static str)],
     ) -> TestServer {
-        use http_test_server::http::{Method, Status};
         let server = TestServer::new().unwrap();
         let resource = server.create_resource(path);
         resource
@@ -138,6 +1062,76 @@ mod tests {
         server
     }
 
+    /// Storage in temporary directory. Removes storage on drop.
+    pub struct TestStorage {
+        /// Holds temp directory and removes it on drop.
+        _temp: TempDir,
+
+        pub storage_manager: StorageManager,
+    }
+
+    impl TestStorage {
+        /// Create Carol middleware using this storage.
+        pub fn middleware(&self, store_policy: StorePolicy) -> CarolMiddleware {
+            CarolMiddleware::new(self.storage_manager.clone(), store_policy)
+        }
+
+        /// Build client with Carol middleware using this storage.
+        pub fn client(&self, store_policy: StorePolicy) -> ClientWithMiddleware {
+            client_with(self.middleware(store_policy))
+        }
+
+        /// Find file in storage by URL.
+        pub async fn find(&self, url: &str) -> Option<File> {
+            self.storage_manager
+                .find_by_source(&FileSource::parse(url))
+                .await
+                .expect("find by source")
+        }
+    }
+
+    /// Build client with given middleware.
+    fn client_with(middleware: CarolMiddleware) -> ClientWithMiddleware {
+        let reqwest_client = reqwest::Client::builder().build().unwrap();
+        reqwest_middleware::ClientBuilder::new(reqwest_client)
+            .with(middleware)
+            .build()
+    }
+
+    /// Create new empty storage.
+    #[fixture]
+    pub async fn storage() -> TestStorage {
+        let temp = tempfile::tempdir().unwrap();
+        let database_path = temp.path().join("carol.sqlite");
+        let cache_dir = temp.path().join("files");
+        fs::create_dir(&cache_dir).await.unwrap();
+        let storage_manager =
+            StorageManager::init(database_path.to_str().unwrap(), &cache_dir, None)
+                .await
+                .expect("init storage manager");
+        TestStorage {
+            _temp: temp,
+            storage_manager,
+        }
+    }
+
+    /// Start new test HTTP server with a single resource.
+    fn server_with_resource(
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        content: &'static str,
+    ) -> (TestServer, Resource, String) {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource(path);
+        resource.status(Status::OK).method(method).body(content);
+        for (header_name, header_value) in headers {
+            resource.header(header_name, header_value);
+        }
+        let url = format!("http://localhost:{}{}", server.port(), path);
+        (server, resource, url)
+    }
+
     #[rstest]
     #[tokio::test]
     async fn test_middleware(test_server: &TestServer) {
@@ -153,12 +1147,12 @@ mod tests {
             .expect("init storage manager");
         let reqwest_client = reqwest::Client::builder().build().unwrap();
         let client = reqwest_middleware::ClientBuilder::new(reqwest_client)
-            .with(CarolMiddleware {
+            .with(CarolMiddleware::new(
                 storage_manager,
-                store_policy: StorePolicy::ExpiresAfterNotUsedFor {
+                StorePolicy::ExpiresAfterNotUsedFor {
                     duration: Duration::from_secs(3600),
                 },
-            })
+            ))
             .build();
 
         let response = client.get(&url).send().await.expect("get URL");
@@ -171,4 +1165,1044 @@ mod tests {
         assert_eq!(&content, DEFAULT_CONTENT);
         println!("{:#?}", file);
     }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_middleware_storage_error(test_server: &TestServer) {
+        let temp = tempfile::tempdir().unwrap();
+        let database_path = temp.path().join("carol.sqlite");
+        let database_url = database_path.to_str().unwrap();
+        let cache_dir = temp.path().join("files");
+        fs::create_dir(&cache_dir).await.unwrap();
+        let url = format!("http://localhost:{}/hello.txt", test_server.port());
+
+        let storage_manager = StorageManager::init(database_url, &cache_dir, None)
+            .await
+            .expect("init storage manager");
+        let reqwest_client = reqwest::Client::builder().build().unwrap();
+        let client = reqwest_middleware::ClientBuilder::new(reqwest_client)
+            .with(CarolMiddleware::new(
+                storage_manager.clone(),
+                StorePolicy::StoreForever,
+            ))
+            .build();
+
+        // Storage directory disappears, so the file can't be written
+        fs::remove_dir(&cache_dir).await.unwrap();
+
+        let error = client
+            .get(&url)
+            .send()
+            .await
+            .expect_err("storage must fail");
+        let reqwest_middleware::Error::Middleware(error) = error else {
+            panic!("expected middleware error, got {:?}", error);
+        };
+        let error = error
+            .downcast_ref::<CarolMiddlewareError<DatabaseError>>()
+            .expect("downcast to CarolMiddlewareError");
+        assert!(matches!(
+            error,
+            CarolMiddlewareError::StorageError(StorageError::IoError(..))
+        ));
+
+        // Failed entry must be reverted
+        let source = FileSource::parse(&url);
+        assert!(storage_manager
+            .find_by_source(&source)
+            .await
+            .expect("find by source")
+            .is_none());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_unsafe_method_passed_through(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::POST, "/upload", &[], DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let response = client.post(&url).send().await.expect("post URL");
+        assert_eq!(response.text().await.unwrap(), DEFAULT_CONTENT);
+        assert_eq!(resource.request_count(), 1);
+        assert!(storage.find(&url).await.is_none());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_fresh_file_served_from_storage(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let stored = client.get(&url).send().await.expect("get URL");
+        let stored = stored.json::<File>().await.expect("deserialize response");
+        let cached = client.get(&url).send().await.expect("get URL");
+        assert_eq!(cached.status(), StatusCode::OK);
+        let cached = cached.json::<File>().await.expect("deserialize response");
+        assert_eq!(cached.id, stored.id);
+        // Serving the stored file is recorded as its use
+        assert_eq!(cached.metadata.uses, stored.metadata.uses + 1);
+        assert!(cached.metadata.last_used >= stored.metadata.last_used);
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_file_expires_after_uses(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/job-input", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::ExpiresAfterUses { count: 1 });
+
+        let stored = client.get(&url).send().await.expect("get URL");
+        let stored = stored.json::<File>().await.expect("deserialize response");
+        assert_eq!(stored.metadata.uses, 0);
+        // Response without content is not a use
+        client.head(&url).send().await.expect("head URL");
+        let used = client.get(&url).send().await.expect("get URL");
+        let used = used.json::<File>().await.expect("deserialize response");
+        assert_eq!(used.id, stored.id);
+        assert_eq!(used.metadata.uses, 1);
+        assert_eq!(resource.request_count(), 1);
+
+        let refetched = client.get(&url).send().await.expect("get URL");
+        let refetched = refetched
+            .json::<File>()
+            .await
+            .expect("deserialize response");
+        assert_eq!(refetched.metadata.uses, 0);
+        assert_eq!(resource.request_count(), 2);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_expired_file_replaced(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::ExpiresAfter {
+            duration: Duration::ZERO,
+        });
+
+        let first = client.get(&url).send().await.expect("get URL");
+        let first = first.json::<File>().await.expect("deserialize response");
+        let second = client.get(&url).send().await.expect("get URL");
+        let second = second.json::<File>().await.expect("deserialize response");
+        assert!(second.metadata.created > first.metadata.created);
+        assert_eq!(resource.request_count(), 2);
+        let content = fs::read_to_string(&second.metadata.path)
+            .await
+            .expect("read file content");
+        assert_eq!(&content, DEFAULT_CONTENT);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_no_cache_request_revalidates(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        client.get(&url).send().await.expect("get URL");
+        client
+            .get(&url)
+            .header(CACHE_CONTROL, "no-cache")
+            .send()
+            .await
+            .expect("get URL");
+        assert_eq!(resource.request_count(), 2);
+        assert!(storage.find(&url).await.is_some());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_head_answered_from_storage(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        client.get(&url).send().await.expect("get URL");
+        let response = client.head(&url).send().await.expect("head URL");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_LENGTH).unwrap(),
+            &DEFAULT_CONTENT.len().to_string()
+        );
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_head_not_stored(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        // Test server does not support HEAD and responds with an error
+        let response = client.head(&url).send().await.expect("head URL");
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resource.request_count(), 0);
+        assert!(storage.find(&url).await.is_none());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_not_storable_response_passed_through(#[future] storage: TestStorage) {
+        let (_server, _resource, url) = server_with_resource(
+            Method::GET,
+            "/hello.txt",
+            &[("Cache-Control", "no-store")],
+            DEFAULT_CONTENT,
+        );
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(response.text().await.unwrap(), DEFAULT_CONTENT);
+        assert!(storage.find(&url).await.is_none());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_key_headers(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_key_headers([AUTHORIZATION]),
+        );
+
+        let get = async |token: &str| {
+            let response = client
+                .get(&url)
+                .header(AUTHORIZATION, token)
+                .send()
+                .await
+                .expect("get URL");
+            response.json::<File>().await.expect("deserialize response")
+        };
+        let alice = get("alice-secret").await;
+        let bob = get("bob-secret").await;
+        assert_ne!(alice.metadata.source, bob.metadata.source);
+        assert_eq!(resource.request_count(), 2);
+        assert_eq!(get("alice-secret").await.id, alice.id);
+        assert_eq!(resource.request_count(), 2);
+        assert!(!alice.metadata.source.as_str().contains("alice-secret"));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_vary(#[future] storage: TestStorage) {
+        let (_server, resource, url) = server_with_resource(
+            Method::GET,
+            "/hello.txt",
+            &[("Vary", "Accept")],
+            DEFAULT_CONTENT,
+        );
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let get = async |accept: &str| {
+            let response = client
+                .get(&url)
+                .header(ACCEPT, accept)
+                .send()
+                .await
+                .expect("get URL");
+            response.json::<File>().await.expect("deserialize response")
+        };
+        let plain = get("text/plain").await;
+        let html = get("text/html").await;
+        assert_ne!(plain.metadata.source, html.metadata.source);
+        assert_eq!(resource.request_count(), 2);
+        assert_eq!(get("text/plain").await.id, plain.id);
+        assert_eq!(resource.request_count(), 2);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_custom_cache_key(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_cache_key(|req| FileSource::Custom(req.url().path().to_string())),
+        );
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(
+            file.metadata.source,
+            FileSource::Custom("/hello.txt".into())
+        );
+        client.get(&url).send().await.expect("get URL");
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    const RESUMABLE_CONTENT: &[u8] = b"Hello resumable world";
+
+    /// Test HTTP server for a single resource supporting byte ranges.
+    ///
+    /// The first `failures` responses are cut off after `cut_at` bytes of the body.
+    /// `Range` headers of received requests are recorded.
+    struct RangeServer {
+        url: String,
+        ranges: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl RangeServer {
+        async fn start(failures: usize, cut_at: usize) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!(
+                "http://localhost:{}/resumable.txt",
+                listener.local_addr().unwrap().port()
+            );
+            let ranges = Arc::new(Mutex::new(Vec::new()));
+            let recorded = ranges.clone();
+            tokio::spawn(async move {
+                for served in 0.. {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let headers = read_request_headers(&mut socket).await;
+                    let range = headers.get("range").cloned();
+                    recorded.lock().unwrap().push(range.clone());
+
+                    let start = range
+                        .filter(|_| headers.get("if-range").map(String::as_str) == Some("\"v1\""))
+                        .and_then(|range| {
+                            range
+                                .strip_prefix("bytes=")?
+                                .strip_suffix('-')?
+                                .parse()
+                                .ok()
+                        });
+                    let total = RESUMABLE_CONTENT.len();
+                    let (status, content_range, body) = match start {
+                        Some(start) => (
+                            "206 Partial Content",
+                            format!("Content-Range: bytes {}-{}/{}\r\n", start, total - 1, total),
+                            &RESUMABLE_CONTENT[start..],
+                        ),
+                        None => ("200 OK", String::new(), RESUMABLE_CONTENT),
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}ETag: \"v1\"\r\n\
+                         Accept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len(),
+                        content_range,
+                    );
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    let body = match served < failures {
+                        true => &body[..cut_at.min(body.len())],
+                        false => body,
+                    };
+                    socket.write_all(body).await.unwrap();
+                    socket.shutdown().await.unwrap();
+                }
+            });
+            Self { url, ranges }
+        }
+
+        fn ranges(&self) -> Vec<Option<String>> {
+            self.ranges.lock().unwrap().clone()
+        }
+    }
+
+    /// Read request head and return its headers with lowercase names.
+    async fn read_request_headers(socket: &mut TcpStream) -> HashMap<String, String> {
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let read = socket.read(&mut buf).await.unwrap();
+            assert!(read > 0, "connection closed before request end");
+            head.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8(head)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_resume_within_request(#[future] storage: TestStorage) {
+        let server = RangeServer::start(1, 10).await;
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_resumable_downloads(1),
+        );
+
+        let response = client.get(&server.url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file.status, FileStatus::Ready);
+        assert_eq!(
+            fs::read(&file.metadata.path).await.unwrap(),
+            RESUMABLE_CONTENT
+        );
+        assert_eq!(server.ranges(), vec![None, Some("bytes=10-".to_string())]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_resume_on_next_request(#[future] storage: TestStorage) {
+        let server = RangeServer::start(1, 10).await;
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_resumable_downloads(0),
+        );
+
+        client
+            .get(&server.url)
+            .send()
+            .await
+            .expect_err("download must be interrupted");
+        let partial = storage.find(&server.url).await.expect("partial file");
+        assert_eq!(partial.status, FileStatus::Partial);
+        assert_eq!(partial.metadata.validator.as_deref(), Some("\"v1\""));
+
+        let response = client.get(&server.url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file.status, FileStatus::Ready);
+        assert_eq!(
+            fs::read(&file.metadata.path).await.unwrap(),
+            RESUMABLE_CONTENT
+        );
+        assert_eq!(server.ranges(), vec![None, Some("bytes=10-".to_string())]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_interrupted_download_reverted(#[future] storage: TestStorage) {
+        let server = RangeServer::start(1, 10).await;
+        let client = storage.client(StorePolicy::StoreForever);
+
+        client
+            .get(&server.url)
+            .send()
+            .await
+            .expect_err("download must be interrupted");
+        assert!(storage.find(&server.url).await.is_none());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_transparent_body(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_body_mode(BodyMode::Transparent),
+        );
+
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(response.text().await.expect("read body"), DEFAULT_CONTENT);
+        let file = storage.find(&url).await.expect("stored file");
+        assert_eq!(file.status, FileStatus::Ready);
+        assert_eq!(
+            fs::read_to_string(&file.metadata.path).await.unwrap(),
+            DEFAULT_CONTENT
+        );
+
+        // Stored file is served with its content
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            DEFAULT_CONTENT.len().to_string()
+        );
+        assert_eq!(response.text().await.expect("read body"), DEFAULT_CONTENT);
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    /// Start test HTTP server, which sends the first `cut_at` bytes of [`RESUMABLE_CONTENT`]
+    /// and waits for `gate` to be notified before sending the rest.
+    ///
+    /// Returns URL of the resource and counter of received requests.
+    async fn gated_server(cut_at: usize, gate: Arc<Notify>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/gated.txt",
+            listener.local_addr().unwrap().port()
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                read_request_headers(&mut socket).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                let gate = gate.clone();
+                tokio::spawn(async move {
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        RESUMABLE_CONTENT.len()
+                    );
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    socket
+                        .write_all(&RESUMABLE_CONTENT[..cut_at])
+                        .await
+                        .unwrap();
+                    gate.notified().await;
+                    socket
+                        .write_all(&RESUMABLE_CONTENT[cut_at..])
+                        .await
+                        .unwrap();
+                    socket.shutdown().await.unwrap();
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_transparent_body_streams_while_storing(#[future] storage: TestStorage) {
+        let gate = Arc::new(Notify::new());
+        let (url, requests) = gated_server(10, gate.clone()).await;
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_body_mode(BodyMode::Transparent),
+        );
+
+        // Content arrives before the whole file is stored
+        let mut first = client.get(&url).send().await.expect("get URL");
+        let chunk = first
+            .chunk()
+            .await
+            .expect("read chunk")
+            .expect("first chunk");
+        assert_eq!(&chunk[..], &RESUMABLE_CONTENT[..10]);
+        let file = storage.find(&url).await.expect("file being stored");
+        assert_eq!(file.status, FileStatus::Pending);
+
+        // Concurrent request reads the growing file
+        let second = client.get(&url).send().await.expect("get URL");
+        gate.notify_one();
+        let mut first_content = chunk.to_vec();
+        first_content.extend_from_slice(&first.bytes().await.expect("read body"));
+        assert_eq!(first_content, RESUMABLE_CONTENT);
+        assert_eq!(
+            &second.bytes().await.expect("read body")[..],
+            RESUMABLE_CONTENT
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_transparent_body_resumed(#[future] storage: TestStorage) {
+        let server = RangeServer::start(1, 10).await;
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_resumable_downloads(0)
+                .with_body_mode(BodyMode::Transparent),
+        );
+
+        let response = client.get(&server.url).send().await.expect("get URL");
+        response
+            .bytes()
+            .await
+            .expect_err("download must be interrupted");
+        let partial = storage.find(&server.url).await.expect("partial file");
+        assert_eq!(partial.status, FileStatus::Partial);
+
+        // Body has stored part followed by the rest of content
+        let response = client.get(&server.url).send().await.expect("get URL");
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            RESUMABLE_CONTENT.len().to_string()
+        );
+        assert_eq!(
+            &response.bytes().await.expect("read body")[..],
+            RESUMABLE_CONTENT
+        );
+        assert_eq!(server.ranges(), vec![None, Some("bytes=10-".to_string())]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_stale_if_error_server_error(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::ExpiresAfter {
+                    duration: Duration::ZERO,
+                })
+                .with_stale_if_error(Duration::from_secs(3600)),
+        );
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let stored = response.json::<File>().await.expect("deserialize response");
+        resource.status(Status::InternalServerError);
+
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[WARNING], STALE_WARNING);
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file, stored);
+        assert_eq!(resource.request_count(), 2);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_stale_if_error_request_directive(#[future] storage: TestStorage) {
+        let (server, _resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::ExpiresAfter {
+            duration: Duration::ZERO,
+        });
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let stored = response.json::<File>().await.expect("deserialize response");
+        // Origin becomes unreachable
+        drop(server);
+
+        client
+            .get(&url)
+            .send()
+            .await
+            .expect_err("stale file must not be served by default");
+        let response = client
+            .get(&url)
+            .header(CACHE_CONTROL, "stale-if-error=3600")
+            .send()
+            .await
+            .expect("get URL");
+        assert_eq!(response.headers()[WARNING], STALE_WARNING);
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file, stored);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_stale_if_error_window_exceeded(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::ExpiresAfter {
+                    duration: Duration::ZERO,
+                })
+                .with_stale_if_error(Duration::ZERO),
+        );
+
+        client.get(&url).send().await.expect("get URL");
+        resource.status(Status::InternalServerError);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let error = client
+            .get(&url)
+            .send()
+            .await
+            .expect_err("stale file must not be served");
+        assert_eq!(error.status(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[rstest]
+    #[case(Status::NotFound, StatusCode::NOT_FOUND)]
+    #[case(Status::Gone, StatusCode::GONE)]
+    #[tokio::test]
+    #[awt]
+    async fn test_negative_caching(
+        #[future] storage: TestStorage,
+        #[case] origin_status: Status,
+        #[case] expected: StatusCode,
+    ) {
+        let (_server, resource, url) = server_with_resource(Method::GET, "/missing.txt", &[], "");
+        resource.status(origin_status);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_negative_caching(Duration::from_secs(60)),
+        );
+
+        for _ in 0..2 {
+            let error = client.get(&url).send().await.expect_err("missing resource");
+            assert_eq!(error.status(), Some(expected));
+        }
+        let response = client.head(&url).send().await.expect("head URL");
+        assert_eq!(response.status(), expected);
+        assert_eq!(resource.request_count(), 1);
+
+        let file = storage.find(&url).await.expect("negative result");
+        assert_eq!(file.status, FileStatus::Ready);
+        assert_eq!(file.metadata.negative_status, Some(expected.as_u16()));
+        assert_eq!(
+            file.metadata.store_policy,
+            StorePolicy::ExpiresAfter {
+                duration: Duration::from_secs(60)
+            }
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_negative_result_expires(#[future] storage: TestStorage) {
+        let (_server, resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", DEFAULT_HEADERS, DEFAULT_CONTENT);
+        resource.status(Status::NotFound);
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_negative_caching(Duration::ZERO),
+        );
+
+        client.get(&url).send().await.expect_err("missing resource");
+        resource.status(Status::OK);
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file.metadata.negative_status, None);
+        assert_eq!(
+            fs::read_to_string(&file.metadata.path).await.unwrap(),
+            DEFAULT_CONTENT
+        );
+        assert_eq!(resource.request_count(), 2);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_negative_result_not_stored_by_default(#[future] storage: TestStorage) {
+        let (_server, resource, url) = server_with_resource(Method::GET, "/missing.txt", &[], "");
+        resource.status(Status::NotFound);
+        let client = storage.client(StorePolicy::StoreForever);
+
+        client.get(&url).send().await.expect_err("missing resource");
+        client.get(&url).send().await.expect_err("missing resource");
+        assert_eq!(resource.request_count(), 2);
+        assert!(storage.find(&url).await.is_none());
+    }
+
+    #[rstest]
+    #[case::server_error(Status::InternalServerError)]
+    #[case::not_found(Status::NotFound)]
+    #[tokio::test]
+    #[awt]
+    async fn test_mirrors(#[future] storage: TestStorage, #[case] primary_status: Status) {
+        let (_primary, primary, primary_url) =
+            server_with_resource(Method::GET, "/artifact.txt", &[], "");
+        primary.status(primary_status);
+        let (_mirror, mirror, mirror_url) = server_with_resource(
+            Method::GET,
+            "/artifact.txt",
+            DEFAULT_HEADERS,
+            DEFAULT_CONTENT,
+        );
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_negative_caching(Duration::from_secs(60)),
+        );
+        let source = FileSource::Custom("artifact".to_string());
+        let mirrors = Mirrors::new(source.clone(), [mirror_url.parse().unwrap()]);
+
+        let response = client
+            .get(&primary_url)
+            .with_extension(mirrors.clone())
+            .send()
+            .await
+            .expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file.metadata.source, source);
+        assert_eq!(file.metadata.negative_status, None);
+        assert_eq!(
+            fs::read_to_string(&file.metadata.path).await.unwrap(),
+            DEFAULT_CONTENT
+        );
+
+        // Stored file is found regardless of the mirror
+        let response = client
+            .get(&mirror_url)
+            .with_extension(mirrors)
+            .send()
+            .await
+            .expect("get URL");
+        assert_eq!(
+            response
+                .json::<File>()
+                .await
+                .expect("deserialize response")
+                .id,
+            file.id
+        );
+        assert_eq!(primary.request_count(), 1);
+        assert_eq!(mirror.request_count(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_all_mirrors_fail(#[future] storage: TestStorage) {
+        let (_primary, primary, primary_url) =
+            server_with_resource(Method::GET, "/artifact.txt", &[], "");
+        primary.status(Status::InternalServerError);
+        let (_mirror, mirror, mirror_url) =
+            server_with_resource(Method::GET, "/artifact.txt", &[], "");
+        mirror.status(Status::ServiceUnavailable);
+        let client = storage.client(StorePolicy::StoreForever);
+        let source = FileSource::Custom("artifact".to_string());
+
+        let error = client
+            .get(&primary_url)
+            .with_extension(Mirrors::new(source, [mirror_url.parse().unwrap()]))
+            .send()
+            .await
+            .expect_err("all mirrors fail");
+        assert_eq!(error.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(primary.request_count(), 1);
+        assert_eq!(mirror.request_count(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_mirrors_storage_error(#[future] storage: TestStorage) {
+        let (_primary, primary, primary_url) =
+            server_with_resource(Method::GET, "/artifact.txt", &[], DEFAULT_CONTENT);
+        let (_mirror, mirror, mirror_url) =
+            server_with_resource(Method::GET, "/artifact.txt", &[], DEFAULT_CONTENT);
+        let client = storage.client(StorePolicy::StoreForever);
+        let source = FileSource::Custom("artifact".to_string());
+
+        // Storage directory disappears, so the file can't be written
+        let path = storage.storage_manager.path_from_source(&source);
+        fs::remove_dir(path.parent().unwrap()).await.unwrap();
+
+        let error = client
+            .get(&primary_url)
+            .with_extension(Mirrors::new(source, [mirror_url.parse().unwrap()]))
+            .send()
+            .await
+            .expect_err("storage must fail");
+        let reqwest_middleware::Error::Middleware(error) = error else {
+            panic!("expected middleware error, got {:?}", error);
+        };
+        let error = error
+            .downcast_ref::<CarolMiddlewareError<DatabaseError>>()
+            .expect("downcast to CarolMiddlewareError");
+        assert!(matches!(
+            error,
+            CarolMiddlewareError::StorageError(StorageError::IoError(..))
+        ));
+        assert_eq!(primary.request_count(), 1);
+        assert_eq!(mirror.request_count(), 0);
+    }
+
+    #[rstest]
+    #[case::repr_digest(
+        "repr-digest",
+        "sha-256=:ZOyIygCyaOW6GjVnihtTFtIS9PNmskdyMlNKiuyjfzw=:",
+        DigestAlgorithm::Sha256
+    )]
+    #[case::x_checksum(
+        "x-checksum-sha256",
+        "64ec88ca00b268e5ba1a35678a1b5316d212f4f366b2477232534a8aeca37f3c",
+        DigestAlgorithm::Sha256
+    )]
+    #[case::content_md5("content-md5", "PiWWCnnbxptnTNTsZ6csYg==", DigestAlgorithm::Md5)]
+    #[tokio::test]
+    #[awt]
+    async fn test_digest_verified(
+        #[future] storage: TestStorage,
+        #[case] header_name: &str,
+        #[case] header_value: &str,
+        #[case] algorithm: DigestAlgorithm,
+    ) {
+        let (_server, _resource, url) = server_with_resource(
+            Method::GET,
+            DEFAULT_PATH,
+            &[(header_name, header_value)],
+            DEFAULT_CONTENT,
+        );
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(
+            file.metadata.digest,
+            Some(Digest::compute(algorithm, DEFAULT_CONTENT))
+        );
+        assert_eq!(storage.find(&url).await, Some(file));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_digest_mismatch(#[future] storage: TestStorage) {
+        let digest = Digest::compute(DigestAlgorithm::Sha256, "Goodbye world");
+        let header_value = format!("sha-256=:{}:", BASE64_STANDARD.encode(&digest.value));
+        let (_server, _resource, url) = server_with_resource(
+            Method::GET,
+            DEFAULT_PATH,
+            &[("repr-digest", &header_value)],
+            DEFAULT_CONTENT,
+        );
+        let client = storage.client(StorePolicy::StoreForever);
+
+        let error = client.get(&url).send().await.expect_err("digest mismatch");
+        let reqwest_middleware::Error::Middleware(error) = error else {
+            panic!("expected middleware error, got {:?}", error);
+        };
+        let error = error
+            .downcast_ref::<CarolMiddlewareError<DatabaseError>>()
+            .expect("downcast to CarolMiddlewareError");
+        assert!(matches!(
+            error,
+            CarolMiddlewareError::StorageError(StorageError::DigestMismatch { expected, .. })
+                if *expected == digest
+        ));
+        // Mismatching file is not stored
+        assert!(storage.find(&url).await.is_none());
+
+        // Without verification the file is stored as is
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_digest_verification(false),
+        );
+        let response = client.get(&url).send().await.expect("get URL");
+        let file = response.json::<File>().await.expect("deserialize response");
+        assert_eq!(file.metadata.digest, None);
+    }
+
+    #[rstest]
+    #[case::max_size(5, None, false)]
+    #[case::max_size_fits(DEFAULT_CONTENT.len() as u64, None, true)]
+    #[case::type_not_allowed(u64::MAX, Some("application/gzip"), false)]
+    #[case::type_allowed(u64::MAX, Some("text/*"), true)]
+    #[tokio::test]
+    #[awt]
+    async fn test_filters(
+        #[future] storage: TestStorage,
+        #[case] max_size: u64,
+        #[case] content_type: Option<&str>,
+        #[case] expected_stored: bool,
+    ) {
+        let content_length = DEFAULT_CONTENT.len().to_string();
+        let headers = [
+            ("Content-Type", "text/plain"),
+            ("Content-Length", &content_length),
+        ];
+        let (_server, _resource, url) =
+            server_with_resource(Method::GET, "/hello.txt", &headers, DEFAULT_CONTENT);
+        let mut middleware = storage
+            .middleware(StorePolicy::StoreForever)
+            .with_max_size(max_size);
+        if let Some(content_type) = content_type {
+            middleware = middleware.with_content_types([content_type]);
+        }
+        let client = client_with(middleware);
+
+        let response = client.get(&url).send().await.expect("get URL");
+        let stored = storage.find(&url).await;
+        assert_eq!(stored.is_some(), expected_stored);
+        if !expected_stored {
+            // Filtered response is returned untouched
+            assert_eq!(response.text().await.expect("read body"), DEFAULT_CONTENT);
+        }
+    }
+
+    /// Start test HTTP server, which sends [`RESUMABLE_CONTENT`] without `Content-Length`.
+    async fn unsized_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/unsized.txt",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                read_request_headers(&mut socket).await;
+                tokio::spawn(async move {
+                    let head = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n";
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    socket.write_all(RESUMABLE_CONTENT).await.unwrap();
+                    socket.shutdown().await.unwrap();
+                });
+            }
+        });
+        url
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_max_size_exceeded_while_storing(#[future] storage: TestStorage) {
+        let url = unsized_server().await;
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_max_size(5),
+        );
+
+        let error = client.get(&url).send().await.expect_err("too large");
+        let reqwest_middleware::Error::Middleware(error) = error else {
+            panic!("expected middleware error, got {:?}", error);
+        };
+        let error = error
+            .downcast_ref::<CarolMiddlewareError<DatabaseError>>()
+            .expect("downcast to CarolMiddlewareError");
+        assert!(matches!(
+            error,
+            CarolMiddlewareError::ResponseTooLarge { max_size: 5 }
+        ));
+        assert!(storage.find(&url).await.is_none());
+
+        // Transparent body yields the whole content
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_max_size(5)
+                .with_body_mode(BodyMode::Transparent),
+        );
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(
+            response.bytes().await.expect("read body").as_ref(),
+            RESUMABLE_CONTENT
+        );
+        assert!(storage.find(&url).await.is_none());
+
+        // Response without length may be skipped upfront
+        let client = client_with(
+            storage
+                .middleware(StorePolicy::StoreForever)
+                .with_content_length_required(true),
+        );
+        let response = client.get(&url).send().await.expect("get URL");
+        assert_eq!(
+            response.bytes().await.expect("read body").as_ref(),
+            RESUMABLE_CONTENT
+        );
+        assert!(storage.find(&url).await.is_none());
+    }
 }
@@ -0,0 +1,85 @@
+//! Mirrors of a single logical source.
+
+use carol::FileSource;
+use reqwest_middleware::reqwest::Url;
+
+/// Mirrors of the requested resource.
+///
+/// Attach to a request with
+/// [`RequestBuilder::with_extension`](reqwest_middleware::RequestBuilder::with_extension).
+/// If fetching the request URL fails, mirror URLs are tried in turn. The response is stored
+/// under the canonical `source` regardless of the URL it was fetched from, so it's found by
+/// requests to any of the mirrors.
+///
+/// # Example
+///
+/// ```rust
+/// # async fn test(client: reqwest_middleware::ClientWithMiddleware) {
+/// use carol_reqwest_middleware::storage::FileSource;
+/// use carol_reqwest_middleware::Mirrors;
+///
+/// let mirrors = Mirrors::new(
+///     FileSource::Custom("artifact-1.0.tar.gz".to_string()),
+///     ["https://mirror.example.com/artifact-1.0.tar.gz".parse().unwrap()],
+/// );
+/// let response = client
+///     .get("https://example.com/artifact-1.0.tar.gz")
+///     .with_extension(mirrors)
+///     .send()
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirrors {
+    /// Canonical source, under which the response is stored.
+    pub source: FileSource,
+
+    /// Mirror URLs in order of preference.
+    pub urls: Vec<Url>,
+}
+
+impl Mirrors {
+    /// Create mirrors of canonical `source`.
+    pub fn new(source: FileSource, urls: impl IntoIterator<Item = Url>) -> Self {
+        Self {
+            source,
+            urls: urls.into_iter().collect(),
+        }
+    }
+
+    /// URLs to fetch the resource from: `url` followed by mirror URLs other than `url`.
+    pub(crate) fn candidates(&self, url: &Url) -> Vec<Url> {
+        let mut candidates = vec![url.clone()];
+        for mirror in &self.urls {
+            if !candidates.contains(mirror) {
+                candidates.push(mirror.clone());
+            }
+        }
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mirrors;
+    use carol::FileSource;
+    use reqwest_middleware::reqwest::Url;
+    use rstest::rstest;
+
+    fn urls(values: &[&str]) -> Vec<Url> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[rstest]
+    #[case(&[], &["https://a.com/f"])]
+    #[case(&["https://b.com/f", "https://c.com/f"], &["https://a.com/f", "https://b.com/f", "https://c.com/f"])]
+    #[case(&["https://b.com/f", "https://a.com/f"], &["https://a.com/f", "https://b.com/f"])]
+    #[case(&["https://b.com/f", "https://b.com/f"], &["https://a.com/f", "https://b.com/f"])]
+    #[trace]
+    fn test_candidates(#[case] mirrors: &[&str], #[case] expected: &[&str]) {
+        let mirrors = Mirrors::new(FileSource::Custom("file".to_string()), urls(mirrors));
+        let url = "https://a.com/f".parse().unwrap();
+        assert_eq!(mirrors.candidates(&url), urls(expected));
+    }
+}
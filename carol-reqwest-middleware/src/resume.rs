@@ -0,0 +1,88 @@
+//! Helpers for resuming interrupted downloads with `Range` requests (RFC 9110, section 14).
+
+use http::header::{ACCEPT_RANGES, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+use http::HeaderMap;
+
+/// Get validator to be sent in `If-Range` header when resuming download of the response.
+///
+/// Returns `None` if the origin does not accept byte ranges or provides no validator, which
+/// can be used in `If-Range` (strong `ETag` or `Last-Modified`).
+pub(crate) fn range_validator(headers: &HeaderMap) -> Option<String> {
+    let accepts_bytes = headers
+        .get_all(ACCEPT_RANGES)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"));
+    if !accepts_bytes {
+        return None;
+    }
+    let etag = headers
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"));
+    etag.or_else(|| {
+        headers
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+    })
+    .map(ToOwned::to_owned)
+}
+
+/// Get first byte position from `Content-Range` header of partial response.
+pub(crate) fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_range_start, range_validator};
+    use http::header::{ACCEPT_RANGES, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+    use http::{HeaderMap, HeaderName};
+    use rstest::rstest;
+
+    fn headers(values: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    const DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    #[rstest]
+    #[case::etag(&[(ACCEPT_RANGES, "bytes"), (ETAG, "\"v1\"")], Some("\"v1\""))]
+    #[case::etag_preferred(
+        &[(ACCEPT_RANGES, "bytes"), (ETAG, "\"v1\""), (LAST_MODIFIED, DATE)],
+        Some("\"v1\""),
+    )]
+    #[case::weak_etag(&[(ACCEPT_RANGES, "bytes"), (ETAG, "W/\"v1\"")], None)]
+    #[case::weak_etag_last_modified(
+        &[(ACCEPT_RANGES, "bytes"), (ETAG, "W/\"v1\""), (LAST_MODIFIED, DATE)],
+        Some(DATE),
+    )]
+    #[case::no_validator(&[(ACCEPT_RANGES, "bytes")], None)]
+    #[case::no_ranges(&[(ETAG, "\"v1\"")], None)]
+    #[case::ranges_none(&[(ACCEPT_RANGES, "none"), (ETAG, "\"v1\"")], None)]
+    #[trace]
+    fn test_range_validator(#[case] values: &[(HeaderName, &str)], #[case] expected: Option<&str>) {
+        assert_eq!(range_validator(&headers(values)).as_deref(), expected);
+    }
+
+    #[rstest]
+    #[case("bytes 10-25/26", Some(10))]
+    #[case("bytes 0-25/*", Some(0))]
+    #[case("bytes */26", None)]
+    #[case("items 10-25/26", None)]
+    #[trace]
+    fn test_content_range_start(#[case] value: &str, #[case] expected: Option<u64>) {
+        assert_eq!(
+            content_range_start(&headers(&[(CONTENT_RANGE, value)])),
+            expected
+        );
+    }
+}
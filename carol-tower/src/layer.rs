@@ -0,0 +1,62 @@
+//! Layer wrapping services with [`CarolService`].
+
+use std::sync::Arc;
+
+use carol::sqlite::SqliteStorageDatabase;
+use carol::{FileSource, StorageDatabaseExt, StorageManager, StorePolicy};
+use http::request::Parts;
+use tower_layer::Layer;
+
+use crate::service::{default_cache_key, CacheKeyFn, CarolService};
+
+/// Layer storing responses of the wrapped service in Carol storage.
+///
+/// See [`CarolService`] for details.
+pub struct CarolLayer<D: StorageDatabaseExt = SqliteStorageDatabase> {
+    storage_manager: StorageManager<D>,
+    store_policy: StorePolicy,
+    cache_key: Arc<CacheKeyFn>,
+}
+
+impl<D: StorageDatabaseExt> CarolLayer<D> {
+    /// Create layer storing files in `storage_manager` with given `store_policy`.
+    pub fn new(storage_manager: StorageManager<D>, store_policy: StorePolicy) -> Self {
+        Self {
+            storage_manager,
+            store_policy,
+            cache_key: Arc::new(default_cache_key),
+        }
+    }
+
+    /// Use custom function to compute the cache key of a request.
+    pub fn with_cache_key(
+        mut self,
+        cache_key: impl Fn(&Parts) -> FileSource + Send + Sync + 'static,
+    ) -> Self {
+        self.cache_key = Arc::new(cache_key);
+        self
+    }
+}
+
+impl<D: StorageDatabaseExt> Clone for CarolLayer<D> {
+    fn clone(&self) -> Self {
+        Self {
+            storage_manager: self.storage_manager.clone(),
+            store_policy: self.store_policy,
+            cache_key: self.cache_key.clone(),
+        }
+    }
+}
+
+impl<S, D: StorageDatabaseExt> Layer<S> for CarolLayer<D> {
+    type Service = CarolService<S, D>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CarolService::new(
+            inner,
+            self.storage_manager.clone(),
+            self.store_policy,
+            self.cache_key.clone(),
+        )
+    }
+}
@@ -0,0 +1,51 @@
+//! Caching layer for [`tower`](https://docs.rs/tower) services backed by [`carol`] storage.
+//!
+//! [`CarolLayer`] wraps HTTP client services, e.g. [`hyper`](https://docs.rs/hyper) clients,
+//! with the same semantics as `carol-reqwest-middleware`: successful responses to `GET` requests
+//! are stored under the request URI, and the response body is replaced with JSON-serialized
+//! [`File`](carol::File).
+//!
+//! # Example
+//!
+//! ```rust
+//! # async fn test(cache_dir: &str, database_url: &str) {
+//! use carol_tower::storage::{File, StorageManager, StorePolicy};
+//! use carol_tower::CarolLayer;
+//! use http_body_util::BodyExt;
+//! use tower::{ServiceBuilder, ServiceExt};
+//!
+//! let storage_manager = StorageManager::init(database_url, cache_dir, None).await.unwrap();
+//! let client = hyper_util::client::legacy::Client::builder(
+//!     hyper_util::rt::TokioExecutor::new(),
+//! )
+//! .build_http::<String>();
+//! let service = ServiceBuilder::new()
+//!     .layer(CarolLayer::new(storage_manager, StorePolicy::StoreForever))
+//!     .service(client);
+//!
+//! let request = http::Request::get("http://example.com/file.txt")
+//!     .body(String::new())
+//!     .unwrap();
+//! let response = service.oneshot(request).await.unwrap();
+//! let body = response.into_body().collect().await.unwrap().to_bytes();
+//! let file: File = serde_json::from_slice(&body).unwrap();
+//!
+//! // Downloaded file is stored at 'file.metadata.path'
+//! let content = std::fs::read(&file.metadata.path);
+//! # }
+//! ```
+
+mod layer;
+mod service;
+
+#[doc(no_inline)]
+pub use carol as storage;
+
+pub use layer::CarolLayer;
+pub use service::{CacheKeyFn, CarolBody, CarolService};
+
+/// Error type of [`CarolService`].
+///
+/// Errors of the inner service and its response body are passed as is. Storage errors are
+/// [`StorageError`](carol::StorageError) and can be retrieved with `downcast_ref`.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
@@ -0,0 +1,527 @@
+//! Service storing responses of the inner service.
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use carol::chrono::{DateTime, Utc};
+use carol::sqlite::SqliteStorageDatabase;
+use carol::{File, FileSource, FileStatus, StorageDatabaseExt, StorageManager, StorePolicy};
+use content_disposition::parse_content_disposition;
+use futures_util::TryStreamExt;
+use http::header::{
+    AGE, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    LAST_MODIFIED,
+};
+use http::request::Parts;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use http_body::Body;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Empty, Full};
+use tower_service::Service;
+
+use crate::BoxError;
+
+/// Function computing cache key of a request.
+pub type CacheKeyFn = dyn Fn(&Parts) -> FileSource + Send + Sync;
+
+/// Body of responses returned by [`CarolService`].
+pub type CarolBody = UnsyncBoxBody<Bytes, BoxError>;
+
+/// Default cache key: request URI.
+pub(crate) fn default_cache_key(parts: &Parts) -> FileSource {
+    FileSource::parse(&parts.uri.to_string())
+}
+
+/// Service storing responses of the inner service in Carol storage.
+///
+/// Only `GET` and `HEAD` requests are handled, other requests are passed through untouched.
+///
+/// `200 OK` response to `GET` request is stored unless the request or the response has
+/// `Cache-Control: no-store`, and the response body is replaced with JSON-serialized [`File`].
+/// Other responses are returned untouched. Fresh stored files are served without calling the
+/// inner service, expired ones are replaced once the inner service responds with a new one to
+/// store. `HEAD` requests are answered from stored file metadata when possible.
+///
+/// Responses are stored under the request URI by default, use
+/// [`CarolLayer::with_cache_key`](crate::CarolLayer::with_cache_key) to compute the key
+/// differently. File name is taken from `Content-Disposition` header or the URI path.
+pub struct CarolService<S, D: StorageDatabaseExt = SqliteStorageDatabase> {
+    inner: S,
+    storage_manager: StorageManager<D>,
+    store_policy: StorePolicy,
+    cache_key: Arc<CacheKeyFn>,
+}
+
+impl<S, D: StorageDatabaseExt> CarolService<S, D> {
+    pub(crate) fn new(
+        inner: S,
+        storage_manager: StorageManager<D>,
+        store_policy: StorePolicy,
+        cache_key: Arc<CacheKeyFn>,
+    ) -> Self {
+        Self {
+            inner,
+            storage_manager,
+            store_policy,
+            cache_key,
+        }
+    }
+}
+
+impl<S: Clone, D: StorageDatabaseExt> Clone for CarolService<S, D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            storage_manager: self.storage_manager.clone(),
+            store_policy: self.store_policy,
+            cache_key: self.cache_key.clone(),
+        }
+    }
+}
+
+impl<S, D, ReqBody, ResBody> Service<Request<ReqBody>> for CarolService<S, D>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    D: StorageDatabaseExt + 'static,
+    D::Error: 'static,
+    ReqBody: Send + 'static,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<CarolBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // The inner service, which is ready, is taken, its clone is left in place
+        let clone = self.clone();
+        let service = std::mem::replace(self, clone);
+        Box::pin(service.handle(req))
+    }
+}
+
+impl<S, D> CarolService<S, D>
+where
+    D: StorageDatabaseExt + 'static,
+    D::Error: 'static,
+{
+    async fn handle<ReqBody, ResBody>(
+        mut self,
+        req: Request<ReqBody>,
+    ) -> Result<Response<CarolBody>, BoxError>
+    where
+        S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+        S::Error: Into<BoxError>,
+        ResBody: Body<Data = Bytes> + Send + 'static,
+        ResBody::Error: Into<BoxError>,
+    {
+        let method = req.method().clone();
+        if (method != Method::GET && method != Method::HEAD) || has_no_store(req.headers()) {
+            return self.forward(req).await;
+        }
+
+        let (parts, body) = req.into_parts();
+        let source = (self.cache_key)(&parts);
+        let req = Request::from_parts(parts, body);
+        if let Some(file) = self.storage_manager.find_by_source(&source).await? {
+            if file.status == FileStatus::Ready && !file.metadata.is_expired(Utc::now()) {
+                // Only responses with content count as uses of the file
+                if method == Method::HEAD {
+                    return stored_response(&method, &file);
+                }
+                let file = self.storage_manager.use_file(file.id).await?;
+                return stored_response(&method, &file);
+            }
+        }
+
+        // Nothing to store from the response to HEAD request
+        if method == Method::HEAD {
+            return self.forward(req).await;
+        }
+
+        let path = req.uri().path().to_owned();
+        let response = self.inner.call(req).await.map_err(Into::into)?;
+        if response.status() != StatusCode::OK || has_no_store(response.headers()) {
+            return Ok(response.map(box_body));
+        }
+
+        // Expired file is removed only when its replacement is fetched, so origin failures don't
+        // destroy it
+        if let Some(file) = self.storage_manager.find_by_source(&source).await? {
+            if file.status == FileStatus::Ready && file.metadata.is_expired(Utc::now()) {
+                self.storage_manager.remove_file(file.id).await?;
+            }
+        }
+
+        let filename = get_filename(response.headers(), &path);
+        let (mut parts, body) = response.into_parts();
+        let stream = BodyDataStream::new(body).map_err(|err| io::Error::other(err.into()));
+        let file = self
+            .storage_manager
+            .add_file_from_stream(source, self.store_policy, filename, Box::pin(stream))
+            .await?;
+
+        // Body is replaced, so headers describing the original body don't apply
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.remove(CONTENT_RANGE);
+        parts
+            .headers
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        let body = Full::new(Bytes::from(serde_json::to_vec(&file)?));
+        Ok(Response::from_parts(parts, box_body(body)))
+    }
+
+    /// Pass the request to the inner service and return its response untouched.
+    async fn forward<ReqBody, ResBody>(
+        mut self,
+        req: Request<ReqBody>,
+    ) -> Result<Response<CarolBody>, BoxError>
+    where
+        S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+        S::Error: Into<BoxError>,
+        ResBody: Body<Data = Bytes> + Send + 'static,
+        ResBody::Error: Into<BoxError>,
+    {
+        let response = self.inner.call(req).await.map_err(Into::into)?;
+        Ok(response.map(box_body))
+    }
+}
+
+/// Build response for a file found in storage.
+///
+/// Response to `HEAD` request has no body and reports size of the stored file
+/// in `Content-Length` header.
+fn stored_response(method: &Method, file: &File) -> Result<Response<CarolBody>, BoxError> {
+    let age = (Utc::now() - file.metadata.created).num_seconds().max(0);
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(AGE, age)
+        .header(LAST_MODIFIED, http_date(file.metadata.created));
+    let response = if method == Method::HEAD {
+        let size = std::fs::metadata(&file.metadata.path)?.len();
+        builder
+            .header(CONTENT_LENGTH, size)
+            .body(box_body(Empty::new()))?
+    } else {
+        let body = Full::new(Bytes::from(serde_json::to_vec(file)?));
+        builder
+            .header(CONTENT_TYPE, "application/json")
+            .body(box_body(body))?
+    };
+    Ok(response)
+}
+
+/// Convert `body` into [`CarolBody`].
+fn box_body<B>(body: B) -> CarolBody
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// Check if `Cache-Control` header forbids storing.
+fn has_no_store(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+/// Format timestamp as HTTP date (RFC 9110, section 5.6.7).
+fn http_date(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Try getting file name from response headers or request URI `path`.
+fn get_filename(headers: &HeaderMap, path: &str) -> Option<String> {
+    // Try getting file name from Content-Disposition first
+    if let Some(value) = headers.get(CONTENT_DISPOSITION) {
+        if let Ok(value_str) = value.to_str() {
+            if let Some(filename) = parse_content_disposition(value_str).filename_full() {
+                return Some(filename);
+            }
+        }
+    }
+    // Try deducing file name from URI
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_filename, has_no_store, CarolBody};
+    use crate::{BoxError, CarolLayer};
+    use bytes::Bytes;
+    use carol::memory::{MemoryDatabaseError, MemoryStorageDatabase};
+    use carol::{File, FileSource, StorageError, StorageManager, StorePolicy};
+    use http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH};
+    use http::{HeaderMap, HeaderName, Method, Request, Response, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use hyper::service::service_fn;
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rstest::rstest;
+    use std::convert::Infallible;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tower::util::BoxCloneService;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    const CONTENT: &str = "Hello world";
+
+    /// Create storage manager with in-memory database, keeping files in `dir`.
+    async fn storage_manager(dir: &Path) -> StorageManager<MemoryStorageDatabase> {
+        StorageManager::builder()
+            .database(MemoryStorageDatabase::new())
+            .dir(dir)
+            .create_dir(true)
+            .build()
+            .await
+            .expect("build storage manager")
+    }
+
+    /// Local hyper server.
+    struct TestServer {
+        url: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    /// Start local hyper server responding to every request with `status` and [`CONTENT`].
+    ///
+    /// `/download` responses have `Content-Disposition` header.
+    async fn test_server(status: StatusCode) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut builder = Response::builder().status(status);
+                    if req.uri().path() == "/download" {
+                        builder = builder
+                            .header(CONTENT_DISPOSITION, "attachment; filename=\"hello.txt\"");
+                    }
+                    let response = builder.body(Full::new(Bytes::from(CONTENT)));
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(socket), service),
+                );
+            }
+        });
+        TestServer { url, requests }
+    }
+
+    type TestService = BoxCloneService<Request<String>, Response<CarolBody>, BoxError>;
+
+    /// Build hyper client wrapped with `layer`.
+    fn client_with(layer: CarolLayer<MemoryStorageDatabase>) -> TestService {
+        let client: Client<HttpConnector, String> =
+            Client::builder(TokioExecutor::new()).build_http();
+        BoxCloneService::new(ServiceBuilder::new().layer(layer).service(client))
+    }
+
+    async fn send(service: &TestService, method: Method, url: &str) -> Response<CarolBody> {
+        let request = Request::builder()
+            .method(method)
+            .uri(url)
+            .body(String::new())
+            .unwrap();
+        service
+            .clone()
+            .oneshot(request)
+            .await
+            .expect("send request")
+    }
+
+    async fn body_bytes(response: Response<CarolBody>) -> Bytes {
+        let body = response.into_body().collect().await.expect("read body");
+        body.to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let temp = tempfile::tempdir().unwrap();
+        let storage_manager = storage_manager(temp.path()).await;
+        let server = test_server(StatusCode::OK).await;
+        let service = client_with(CarolLayer::new(
+            storage_manager.clone(),
+            StorePolicy::StoreForever,
+        ));
+        let url = format!("{}/download", server.url);
+
+        let response = send(&service, Method::GET, &url).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let file: File = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(file.metadata.source.as_str(), url);
+        assert_eq!(file.metadata.filename.as_deref(), Some("hello.txt"));
+        assert_eq!(
+            std::fs::read_to_string(&file.metadata.path).unwrap(),
+            CONTENT
+        );
+
+        // Stored file is served without calling the server
+        let response = send(&service, Method::GET, &url).await;
+        let stored: File = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(stored.id, file.id);
+        assert_eq!(stored.metadata.uses, 1);
+        let response = send(&service, Method::HEAD, &url).await;
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            CONTENT.len().to_string()
+        );
+        assert_eq!(server.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[rstest]
+    #[case::not_found(StatusCode::NOT_FOUND, Method::GET)]
+    #[case::unsafe_method(StatusCode::OK, Method::POST)]
+    #[case::head(StatusCode::OK, Method::HEAD)]
+    #[tokio::test]
+    async fn test_passed_through(#[case] status: StatusCode, #[case] method: Method) {
+        let temp = tempfile::tempdir().unwrap();
+        let storage_manager = storage_manager(temp.path()).await;
+        let server = test_server(status).await;
+        let service = client_with(CarolLayer::new(
+            storage_manager.clone(),
+            StorePolicy::StoreForever,
+        ));
+        let url = format!("{}/file.txt", server.url);
+
+        let response = send(&service, method.clone(), &url).await;
+        assert_eq!(response.status(), status);
+        if method != Method::HEAD {
+            assert_eq!(body_bytes(response).await, CONTENT);
+        }
+        let stored = storage_manager
+            .find_by_source(&FileSource::parse(&url))
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_file_replaced() {
+        let temp = tempfile::tempdir().unwrap();
+        let storage_manager = storage_manager(temp.path()).await;
+        let server = test_server(StatusCode::OK).await;
+        let service = client_with(CarolLayer::new(
+            storage_manager.clone(),
+            StorePolicy::ExpiresAfter {
+                duration: Duration::ZERO,
+            },
+        ));
+        let url = format!("{}/file.txt", server.url);
+
+        send(&service, Method::GET, &url).await;
+        let response = send(&service, Method::GET, &url).await;
+        let file: File = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(file.metadata.filename.as_deref(), Some("file.txt"));
+        assert_eq!(server.requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_file_kept_on_origin_failure() {
+        let temp = tempfile::tempdir().unwrap();
+        let storage_manager = storage_manager(temp.path()).await;
+        let server = test_server(StatusCode::SERVICE_UNAVAILABLE).await;
+        let store_policy = StorePolicy::ExpiresAfter {
+            duration: Duration::ZERO,
+        };
+        let service = client_with(CarolLayer::new(storage_manager.clone(), store_policy));
+        let url = format!("{}/file.txt", server.url);
+        let source = FileSource::parse(&url);
+        let stored = storage_manager
+            .add_file_from_stream(
+                source.clone(),
+                store_policy,
+                None,
+                futures_util::stream::iter([Ok::<_, std::io::Error>(Bytes::from(CONTENT))]),
+            )
+            .await
+            .expect("add file");
+
+        let response = send(&service, Method::GET, &url).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let file = storage_manager.find_by_source(&source).await.unwrap();
+        assert_eq!(file, Some(stored));
+    }
+
+    #[tokio::test]
+    async fn test_storage_error() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache_dir = temp.path().join("files");
+        let storage_manager = storage_manager(&cache_dir).await;
+        let server = test_server(StatusCode::OK).await;
+        let service = client_with(CarolLayer::new(
+            storage_manager.clone(),
+            StorePolicy::StoreForever,
+        ));
+        tokio::fs::remove_dir(&cache_dir).await.unwrap();
+
+        let request = Request::get(format!("{}/file.txt", server.url))
+            .body(String::new())
+            .unwrap();
+        let error = service.oneshot(request).await.expect_err("storage fails");
+        let error = error
+            .downcast_ref::<StorageError<MemoryDatabaseError>>()
+            .expect("downcast to StorageError");
+        assert!(matches!(error, StorageError::IoError(..)));
+    }
+
+    #[rstest]
+    #[case(&[], false)]
+    #[case(&[(CACHE_CONTROL, "no-store")], true)]
+    #[case(&[(CACHE_CONTROL, "max-age=60, No-Store")], true)]
+    #[case(&[(CACHE_CONTROL, "no-cache")], false)]
+    #[trace]
+    fn test_has_no_store(#[case] values: &[(HeaderName, &str)], #[case] expected: bool) {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(name, value.parse().unwrap());
+        }
+        assert_eq!(has_no_store(&headers), expected);
+    }
+
+    #[rstest]
+    #[case(Some("attachment; filename=\"a.txt\""), "/b.txt", Some("a.txt"))]
+    #[case(None, "/path/b.txt", Some("b.txt"))]
+    #[case(Some("inline"), "/", None)]
+    #[trace]
+    fn test_get_filename(
+        #[case] content_disposition: Option<&str>,
+        #[case] path: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = content_disposition {
+            headers.insert(CONTENT_DISPOSITION, value.parse().unwrap());
+        }
+        assert_eq!(get_filename(&headers, path).as_deref(), expected);
+    }
+}
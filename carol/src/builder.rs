@@ -0,0 +1,217 @@
+//! Initialization of storage manager with any storage database.
+
+use std::path::PathBuf;
+
+use tokio::fs;
+
+use crate::database::StorageDatabase;
+use crate::error::StorageError;
+use crate::storage_config::StorageConfig;
+use crate::storage_manager::{check_dir, StorageManager};
+
+/// Builder of [`StorageManager`] with any storage database.
+///
+/// Created by [`StorageManager::builder`]. Database and storage directory must be provided.
+///
+/// ```rust
+/// # tokio_test::block_on(async {
+/// use carol::memory::MemoryStorageDatabase;
+/// use carol::StorageManager;
+///
+/// let manager = StorageManager::builder()
+///     .database(MemoryStorageDatabase::new())
+///     .dir("/tmp/carol-builder")
+///     .create_dir(true)
+///     .build()
+///     .await
+///     .unwrap();
+/// # std::fs::remove_dir_all("/tmp/carol-builder").unwrap();
+/// # })
+/// ```
+#[derive(Debug)]
+pub struct StorageManagerBuilder<D: StorageDatabase> {
+    database: Option<D>,
+    dir: Option<PathBuf>,
+    config: StorageConfig,
+    create_dir: bool,
+}
+
+impl<D: StorageDatabase> Default for StorageManagerBuilder<D> {
+    fn default() -> Self {
+        Self {
+            database: None,
+            dir: None,
+            config: StorageConfig::default(),
+            create_dir: false,
+        }
+    }
+}
+
+impl<D: StorageDatabase> StorageManagerBuilder<D> {
+    /// Storage database, which keeps metadata of the files.
+    pub fn database(mut self, database: D) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// Path to storage directory, where the actual files will reside.
+    ///
+    /// This **must be** an absolute path.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Storage configuration. Defaults to [`StorageConfig::default`].
+    pub fn config(mut self, config: StorageConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Create storage directory with its parents, if it doesn't exist. Disabled by default.
+    pub fn create_dir(mut self, create_dir: bool) -> Self {
+        self.create_dir = create_dir;
+        self
+    }
+
+    /// Check storage directory, prepare the database with [`StorageDatabase::setup`] and build
+    /// storage manager.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if:
+    /// - database or storage directory is not provided
+    /// - `dir` is not absolute
+    /// - `dir` does not exists or is not a directory and is not created
+    /// - setup of the database failed
+    pub async fn build(self) -> Result<StorageManager<D>, StorageError<D::Error>> {
+        let database = self
+            .database
+            .ok_or(StorageError::IncompleteBuilder("database"))?;
+        let dir = self
+            .dir
+            .ok_or(StorageError::IncompleteBuilder("storage directory"))?;
+        if self.create_dir && dir.is_absolute() {
+            fs::create_dir_all(&dir).await?;
+        }
+        let dir = check_dir(&dir)?;
+        database.setup().await?;
+        Ok(StorageManager::with_database(database, dir, self.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StorageManagerBuilder;
+    use crate::error::StorageError;
+    use crate::memory::MemoryStorageDatabase;
+    use crate::sqlite::SqliteStorageDatabase;
+    use crate::storage_config::{EvictionPolicy, StorageConfig};
+    use crate::{FileSource, StorageManager, StorePolicy};
+    use rstest::rstest;
+
+    #[tokio::test]
+    async fn test_build() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            eviction_policy: EvictionPolicy::Fifo,
+            ..Default::default()
+        };
+        let manager = StorageManager::builder()
+            .database(MemoryStorageDatabase::new())
+            .dir(tmp.path())
+            .config(config.clone())
+            .build()
+            .await
+            .expect("build storage manager");
+        assert_eq!(manager.config(), &config);
+    }
+
+    #[rstest]
+    #[case::create(true)]
+    #[case::no_create(false)]
+    #[trace]
+    #[tokio::test]
+    async fn test_create_dir(#[case] create_dir: bool) {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("some/storage");
+        let result = StorageManager::builder()
+            .database(MemoryStorageDatabase::new())
+            .dir(&dir)
+            .create_dir(create_dir)
+            .build()
+            .await;
+        if create_dir {
+            result.expect("build storage manager");
+            assert!(dir.is_dir());
+        } else {
+            assert!(matches!(
+                result,
+                Err(StorageError::StorageDirectoryDoesNotExist)
+            ));
+            assert!(!dir.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relative_dir_is_not_created() {
+        let result = StorageManager::builder()
+            .database(MemoryStorageDatabase::new())
+            .dir("relative/storage")
+            .create_dir(true)
+            .build()
+            .await;
+        assert!(matches!(
+            result,
+            Err(StorageError::StorageDirectoryPathIsNotAbsolute)
+        ));
+        assert!(!std::path::Path::new("relative").exists());
+    }
+
+    #[tokio::test]
+    async fn test_incomplete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let result = StorageManager::builder()
+            .dir(tmp.path())
+            .build()
+            .await
+            .map(|_: StorageManager<MemoryStorageDatabase>| ());
+        assert!(matches!(
+            result,
+            Err(StorageError::IncompleteBuilder("database"))
+        ));
+        let result = StorageManagerBuilder::default()
+            .database(MemoryStorageDatabase::new())
+            .build()
+            .await;
+        assert!(matches!(
+            result,
+            Err(StorageError::IncompleteBuilder("storage directory"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_setup_runs_migrations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let database_url = tmp.path().join("carol.sqlite");
+        let database = SqliteStorageDatabase::connect_pool(database_url.to_str().unwrap(), None)
+            .await
+            .expect("connect to database");
+        let manager = StorageManager::builder()
+            .database(database)
+            .dir(tmp.path())
+            .build()
+            .await
+            .expect("build storage manager");
+        let source = FileSource::parse("https://example.com/file.txt");
+        manager
+            .add_file_from_stream(
+                source,
+                StorePolicy::StoreForever,
+                None,
+                futures_util::stream::iter([Ok::<_, std::io::Error>(bytes::Bytes::from("hi"))]),
+            )
+            .await
+            .expect("add file to migrated database");
+    }
+}
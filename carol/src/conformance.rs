@@ -0,0 +1,509 @@
+//! Conformance tests of storage databases.
+//!
+//! Every storage database must behave in the same way for [`StorageManager`] to work correctly
+//! with it. Functions of this module check this behavior and panic if the database does not
+//! conform. Call them from tests of your database implementation:
+//!
+//! ```rust
+//! # tokio_test::block_on(async {
+//! use carol::memory::MemoryStorageDatabase;
+//!
+//! carol::conformance::run(&MemoryStorageDatabase::new()).await;
+//! # })
+//! ```
+//!
+//! Each check works with files of its own sources, so all checks can use the same database.
+//! Every check can be run only once on the same database.
+//!
+//! [`StorageManager`]: crate::StorageManager
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{SubsecRound, TimeDelta, Utc};
+use futures_util::future::join_all;
+
+use crate::database::{StorageDatabaseError, StorageDatabaseExt};
+use crate::file::{FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
+
+/// Number of concurrent operations in concurrency checks.
+const CONCURRENCY: usize = 16;
+
+/// ID of the file, which is never stored in checked database.
+const MISSING_ID: i64 = i64::MAX;
+
+/// Run all conformance checks on `database`.
+pub async fn run<D: StorageDatabaseExt>(database: &D) {
+    store_get_remove(database).await;
+    unique_source_violation(database).await;
+    unique_path_violation(database).await;
+    status_transitions(database).await;
+    select_by_source_prefix(database).await;
+    store_policy_update(database).await;
+    store_policy_update_by_source_prefix(database).await;
+    uses(database).await;
+    pinning(database).await;
+    stale_selection(database).await;
+    concurrent_inserts(database).await;
+}
+
+/// Check that stored file can be got and selected by source and globally unique ID until it is
+/// removed.
+pub async fn store_get_remove<D: StorageDatabaseExt>(database: &D) {
+    let metadata = FileMetadata {
+        store_policy: StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(3600),
+            not_used_for: Duration::from_secs(60),
+        },
+        pinned: true,
+        ..metadata("store_get_remove")
+    };
+    let id = database.store(metadata.clone()).await.expect("store");
+    let file = database.get(id).await.expect("get stored file");
+    assert_eq!(file.id, id, "ID of stored file");
+    assert_eq!(
+        file.database,
+        database.uri().to_string(),
+        "database of file"
+    );
+    assert_eq!(file.status, FileStatus::Pending, "status of stored file");
+    assert_eq!(file.metadata, metadata, "metadata of stored file");
+    let uid = file.uid.expect("globally unique ID of stored file");
+    assert_eq!(
+        database.select_by_uid(uid).await.expect("select by uid"),
+        Some(file.clone()),
+        "file selected by uid"
+    );
+    assert_eq!(
+        database
+            .select_by_source(&metadata.source)
+            .await
+            .expect("select by source"),
+        [file],
+        "files selected by source"
+    );
+
+    database.remove(id).await.expect("remove");
+    let err = database.get(id).await.expect_err("get removed file");
+    assert!(err.is_not_found(), "get removed file: {err:?}");
+    assert!(
+        database
+            .select_by_source(&metadata.source)
+            .await
+            .expect("select by source")
+            .is_empty(),
+        "removed file is selected by source"
+    );
+    assert!(
+        database
+            .select_by_uid(uid)
+            .await
+            .expect("select by uid")
+            .is_none(),
+        "removed file is selected by uid"
+    );
+    database.remove(id).await.expect("remove removed file");
+
+    // Source can be reused after removal
+    let new_id = database.store(metadata).await.expect("store again");
+    database.remove(new_id).await.expect("remove");
+}
+
+/// Check that file with the same source can't be stored twice.
+///
+/// Storage manager derives paths of files from their sources, so the second file has the same
+/// path too.
+pub async fn unique_source_violation<D: StorageDatabaseExt>(database: &D) {
+    let metadata = metadata("unique_source_violation");
+    let id = database.store(metadata.clone()).await.expect("store");
+    let err = database
+        .store(metadata.clone())
+        .await
+        .expect_err("store file with the same source");
+    assert!(err.is_unique_violation(), "store duplicate: {err:?}");
+    let files = database
+        .select_by_source(&metadata.source)
+        .await
+        .expect("select by source");
+    assert_eq!(
+        files.iter().map(|file| file.id).collect::<Vec<_>>(),
+        [id],
+        "files selected by source"
+    );
+}
+
+/// Check that file with the same path as stored file can't be stored, even with other source.
+pub async fn unique_path_violation<D: StorageDatabaseExt>(database: &D) {
+    let metadata = metadata("unique_path_violation");
+    database.store(metadata.clone()).await.expect("store");
+    let err = database
+        .store(FileMetadata {
+            source: FileSource::parse("https://example.com/conformance/unique_path_violation/2"),
+            ..metadata
+        })
+        .await
+        .expect_err("store file with the same path");
+    assert!(err.is_unique_violation(), "store duplicate path: {err:?}");
+}
+
+/// Check conditional and unconditional status updates.
+pub async fn status_transitions<D: StorageDatabaseExt>(database: &D) {
+    let id = database
+        .store(metadata("status_transitions"))
+        .await
+        .expect("store");
+
+    let file = database
+        .update_status_if(id, FileStatus::Pending, FileStatus::Partial)
+        .await
+        .expect("update status")
+        .expect("status is updated if it is expected");
+    assert_eq!(file.status, FileStatus::Partial, "updated status");
+    assert!(
+        database
+            .update_status_if(id, FileStatus::Pending, FileStatus::Ready)
+            .await
+            .expect("update status")
+            .is_none(),
+        "status is updated if it is not expected"
+    );
+    assert_eq!(
+        database.get(id).await.expect("get").status,
+        FileStatus::Partial,
+        "status after failed update"
+    );
+
+    let file = database
+        .update_status(id, FileStatus::Ready)
+        .await
+        .expect("update status");
+    assert_eq!(file.status, FileStatus::Ready, "updated status");
+    assert_eq!(database.get(id).await.expect("get"), file, "updated file");
+
+    // Only one of concurrent updates of the same status succeeds
+    let updates = join_all(
+        (0..CONCURRENCY)
+            .map(|_| database.update_status_if(id, FileStatus::Ready, FileStatus::ToRemove)),
+    )
+    .await;
+    let updated = updates
+        .into_iter()
+        .map(|result| result.expect("update status"))
+        .filter(Option::is_some)
+        .count();
+    assert_eq!(updated, 1, "number of successful concurrent updates");
+
+    let missing = FileId::from(MISSING_ID);
+    assert!(
+        database
+            .update_status_if(missing, FileStatus::Pending, FileStatus::Ready)
+            .await
+            .expect("update status of missing file")
+            .is_none(),
+        "status of missing file is updated"
+    );
+    let err = database
+        .update_status(missing, FileStatus::Ready)
+        .await
+        .expect_err("update status of missing file");
+    assert!(err.is_not_found(), "update status of missing file: {err:?}");
+}
+
+/// Check that files are selected by exact prefix of their sources, without any wildcards and
+/// ignoring of case.
+pub async fn select_by_source_prefix<D: StorageDatabaseExt>(database: &D) {
+    let mut ids = Vec::new();
+    for name in [
+        "select_by_source_prefix/%_/a",
+        "select_by_source_prefix/%_/b",
+        "select_by_source_prefix/xx/c",
+        "SELECT_BY_SOURCE_PREFIX/%_/d",
+    ] {
+        ids.push(database.store(metadata(name)).await.expect("store"));
+    }
+    let prefix = metadata("select_by_source_prefix/%_/").source;
+    let mut selected = database
+        .select_by_source_prefix(prefix.as_str())
+        .await
+        .expect("select by source prefix")
+        .into_iter()
+        .map(|file| file.id)
+        .collect::<Vec<_>>();
+    selected.sort_by_key(|id| i64::from(*id));
+    assert_eq!(selected, ids[..2], "files selected by source prefix");
+
+    let prefix = metadata("select_by_source_prefix/missing").source;
+    assert!(
+        database
+            .select_by_source_prefix(prefix.as_str())
+            .await
+            .expect("select by source prefix")
+            .is_empty(),
+        "files selected by missing source prefix"
+    );
+}
+
+/// Check that store policy of file can be changed.
+pub async fn store_policy_update<D: StorageDatabaseExt>(database: &D) {
+    let metadata = metadata("store_policy_update");
+    let id = database.store(metadata.clone()).await.expect("store");
+    let store_policy = StorePolicy::ExpiresAfterOrNotUsedFor {
+        duration: Duration::from_secs(60),
+        not_used_for: Duration::from_secs(1),
+    };
+    let file = database
+        .update_store_policy(id, store_policy)
+        .await
+        .expect("update store policy");
+    assert_eq!(file.metadata.store_policy, store_policy, "updated policy");
+    assert_eq!(
+        file.metadata.expires_at(),
+        Some(metadata.last_used + TimeDelta::seconds(1)),
+        "updated expiration"
+    );
+    assert_eq!(
+        FileMetadata {
+            store_policy: metadata.store_policy,
+            ..file.metadata.clone()
+        },
+        metadata,
+        "metadata except of policy is not updated"
+    );
+    assert_eq!(database.get(id).await.expect("get"), file, "updated file");
+
+    let err = database
+        .update_store_policy(FileId::from(MISSING_ID), StorePolicy::StoreForever)
+        .await
+        .expect_err("update store policy of missing file");
+    assert!(
+        err.is_not_found(),
+        "update store policy of missing file: {err:?}"
+    );
+}
+
+/// Check that store policy of files with sources starting with a prefix can be changed at once.
+pub async fn store_policy_update_by_source_prefix<D: StorageDatabaseExt>(database: &D) {
+    let mut files = Vec::new();
+    for name in [
+        "store_policy_update_by_source_prefix/%_/a",
+        "store_policy_update_by_source_prefix/%_/b",
+        "store_policy_update_by_source_prefix/xx/c",
+        "STORE_POLICY_UPDATE_BY_SOURCE_PREFIX/%_/d",
+    ] {
+        let metadata = metadata(name);
+        let id = database.store(metadata.clone()).await.expect("store");
+        files.push((id, metadata));
+    }
+    let store_policy = StorePolicy::ExpiresAfterNotUsedFor {
+        duration: Duration::from_secs(1),
+    };
+    let prefix = metadata("store_policy_update_by_source_prefix/%_/").source;
+    let mut updated = database
+        .update_store_policy_by_source_prefix(prefix.as_str(), store_policy)
+        .await
+        .expect("update store policy by source prefix");
+    updated.sort_by_key(|file| i64::from(file.id));
+    assert_eq!(
+        updated.iter().map(|file| file.id).collect::<Vec<_>>(),
+        [files[0].0, files[1].0],
+        "updated files"
+    );
+    for (file, (_, metadata)) in updated.iter().zip(&files) {
+        assert_eq!(file.metadata.store_policy, store_policy, "updated policy");
+        assert_eq!(
+            file.metadata.expires_at(),
+            Some(metadata.last_used + TimeDelta::seconds(1)),
+            "updated expiration"
+        );
+        assert_eq!(
+            database.get(file.id).await.expect("get"),
+            *file,
+            "updated file"
+        );
+    }
+    for (id, metadata) in &files[2..] {
+        assert_eq!(
+            database.get(*id).await.expect("get").metadata,
+            *metadata,
+            "file with other source prefix"
+        );
+    }
+
+    let prefix = metadata("store_policy_update_by_source_prefix/missing").source;
+    assert!(
+        database
+            .update_store_policy_by_source_prefix(prefix.as_str(), store_policy)
+            .await
+            .expect("update store policy by missing source prefix")
+            .is_empty(),
+        "files updated by missing source prefix"
+    );
+}
+
+/// Check that uses of file are counted and the file expires after its last allowed use.
+pub async fn uses<D: StorageDatabaseExt>(database: &D) {
+    let metadata = FileMetadata {
+        store_policy: StorePolicy::ExpiresAfterUses { count: 2 },
+        ..metadata("uses")
+    };
+    let id = database.store(metadata.clone()).await.expect("store");
+    assert_eq!(database.get(id).await.expect("get").metadata.uses, 0);
+
+    let first_use = metadata.last_used + TimeDelta::seconds(1);
+    let file = database.mark_used(id, first_use).await.expect("mark used");
+    assert_eq!(file.metadata.last_used, first_use, "last used after use");
+    assert_eq!(file.metadata.uses, 1, "uses after use");
+    assert_eq!(file.metadata.expires_at(), None, "expiration after use");
+    assert_eq!(database.get(id).await.expect("get"), file, "used file");
+
+    let last_use = first_use + TimeDelta::seconds(1);
+    let file = database.mark_used(id, last_use).await.expect("mark used");
+    assert_eq!(file.metadata.uses, 2, "uses after last use");
+    assert_eq!(
+        file.metadata.expires_at(),
+        Some(last_use),
+        "expiration after last use"
+    );
+    assert_eq!(
+        FileMetadata {
+            last_used: metadata.last_used,
+            uses: metadata.uses,
+            ..file.metadata.clone()
+        },
+        metadata,
+        "metadata except of uses is not updated"
+    );
+
+    let err = database
+        .mark_used(FileId::from(MISSING_ID), last_use)
+        .await
+        .expect_err("mark missing file as used");
+    assert!(err.is_not_found(), "mark missing file as used: {err:?}");
+}
+
+/// Check that file can be pinned and unpinned.
+pub async fn pinning<D: StorageDatabaseExt>(database: &D) {
+    let metadata = metadata("pinning");
+    let id = database.store(metadata.clone()).await.expect("store");
+
+    let file = database.update_pinned(id, true).await.expect("pin");
+    assert!(file.metadata.pinned, "pinned after pinning");
+    assert_eq!(
+        FileMetadata {
+            pinned: false,
+            ..file.metadata.clone()
+        },
+        metadata,
+        "metadata except of pinned is not updated"
+    );
+    assert_eq!(database.get(id).await.expect("get"), file, "pinned file");
+
+    let file = database.update_pinned(id, false).await.expect("unpin");
+    assert_eq!(file.metadata, metadata, "metadata after unpinning");
+    assert_eq!(database.get(id).await.expect("get"), file, "unpinned file");
+
+    let err = database
+        .update_pinned(FileId::from(MISSING_ID), true)
+        .await
+        .expect_err("pin missing file");
+    assert!(err.is_not_found(), "pin missing file: {err:?}");
+}
+
+/// Check that stale files are selected, unless they are pinned.
+pub async fn stale_selection<D: StorageDatabaseExt>(database: &D) {
+    let now = metadata("stale_selection").created;
+    let past = StorePolicy::ExpiresAt {
+        at: now - TimeDelta::seconds(1),
+    };
+    let current = StorePolicy::ExpiresAt { at: now };
+    let future = StorePolicy::ExpiresAt {
+        at: now + TimeDelta::seconds(1),
+    };
+    let mut ids = Vec::new();
+    for (name, store_policy, pinned) in [
+        ("stale_selection/past", past, false),
+        ("stale_selection/now", current, false),
+        ("stale_selection/future", future, false),
+        ("stale_selection/forever", StorePolicy::StoreForever, false),
+        ("stale_selection/pinned", past, true),
+    ] {
+        let metadata = FileMetadata {
+            store_policy,
+            pinned,
+            ..metadata(name)
+        };
+        ids.push(database.store(metadata).await.expect("store"));
+    }
+    // Other checks may leave stale files
+    let prefix = metadata("stale_selection/").source;
+    let mut selected = database
+        .select_stale(now)
+        .await
+        .expect("select stale")
+        .into_iter()
+        .filter(|file| file.metadata.source.as_str().starts_with(prefix.as_str()))
+        .map(|file| file.id)
+        .collect::<Vec<_>>();
+    selected.sort_by_key(|id| i64::from(*id));
+    assert_eq!(selected, ids[..2], "stale files");
+}
+
+/// Check that concurrently stored files get distinct and globally unique IDs, and that only one
+/// of concurrently stored files with the same source succeeds.
+pub async fn concurrent_inserts<D: StorageDatabaseExt>(database: &D) {
+    let inserted = join_all(
+        (0..CONCURRENCY)
+            .map(|n| database.store(metadata(&format!("concurrent_inserts/{n}"))))
+            .collect::<Vec<_>>(),
+    )
+    .await;
+    let ids = inserted
+        .into_iter()
+        .map(|result| result.expect("store"))
+        .collect::<HashSet<_>>();
+    assert_eq!(ids.len(), CONCURRENCY, "number of distinct IDs");
+    let mut uids = HashSet::new();
+    for id in ids {
+        let file = database.get(id).await.expect("get");
+        assert_eq!(file.id, id, "ID of file");
+        uids.insert(file.uid.expect("globally unique ID of stored file"));
+    }
+    assert_eq!(
+        uids.len(),
+        CONCURRENCY,
+        "number of distinct globally unique IDs"
+    );
+
+    let metadata = metadata("concurrent_inserts/same");
+    let inserted = join_all((0..CONCURRENCY).map(|_| database.store(metadata.clone()))).await;
+    let mut stored = 0;
+    for result in inserted {
+        match result {
+            Ok(_) => stored += 1,
+            Err(err) => assert!(err.is_unique_violation(), "store duplicate: {err:?}"),
+        }
+    }
+    assert_eq!(stored, 1, "number of stored files with the same source");
+}
+
+/// Metadata of file for check `name`.
+fn metadata(name: &str) -> FileMetadata {
+    // Databases may store timestamps with lower precision
+    let now = Utc::now().trunc_subsecs(0);
+    FileMetadata {
+        source: FileSource::parse(&format!("https://example.com/conformance/{name}")),
+        filename: Some(format!("{name}.txt")),
+        path: PathBuf::from(format!("/carol/conformance/{name}")),
+        store_policy: StorePolicy::ExpiresAfter {
+            duration: Duration::from_secs(3600),
+        },
+        created: now,
+        last_used: now,
+        validator: Some("\"etag\"".to_string()),
+        negative_status: None,
+        digest: None,
+        pinned: false,
+        uses: 0,
+    }
+}
@@ -1,6 +1,8 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
 
-use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus};
+use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
 
 pub trait StorageDatabaseError: std::error::Error + Send + Sync {
     fn is_unique_violation(&self) -> bool;
@@ -17,6 +19,14 @@ pub trait StorageDatabase: Clone + Sized + Send + Sync {
 
     fn uri(&self) -> Self::Uri;
 
+    /// Prepare the database for use by storage manager, e.g. apply migrations.
+    ///
+    /// Called by [`StorageManagerBuilder::build`](crate::StorageManagerBuilder::build). Does
+    /// nothing by default.
+    async fn setup(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Put new file into the database.
     async fn store(&self, metadata: FileMetadata) -> Result<FileId, Self::Error>;
 
@@ -30,7 +40,58 @@ pub trait StorageDatabase: Clone + Sized + Send + Sync {
 #[async_trait]
 pub trait StorageDatabaseExt: StorageDatabase {
     async fn select_by_source(&self, source: &FileSource) -> Result<Vec<File>, Self::Error>;
+
+    /// Get file by its globally unique identifier, see [`File::uid`].
+    async fn select_by_uid(&self, uid: Uuid) -> Result<Option<File>, Self::Error>;
+
+    /// Get files with sources starting with `prefix`, see [`FileSource::as_str`].
+    async fn select_by_source_prefix(&self, prefix: &str) -> Result<Vec<File>, Self::Error>;
+
+    /// Get files, which are stale at `now` or earlier (see [`FileMetadata::expires_at`]) and are
+    /// not pinned. Files of any status are selected.
+    async fn select_stale(&self, now: DateTime<Utc>) -> Result<Vec<File>, Self::Error>;
+
     async fn update_status(&self, id: FileId, new_status: FileStatus) -> Result<File, Self::Error>;
+
+    /// Update status of the file only if its current status is `expected`.
+    ///
+    /// Returns updated file or `None` if the file has different status or does not exist.
+    async fn update_status_if(
+        &self,
+        id: FileId,
+        expected: FileStatus,
+        new_status: FileStatus,
+    ) -> Result<Option<File>, Self::Error>;
+
+    /// Replace store policy of the file. The moment when the file becomes stale is recomputed.
+    ///
+    /// Returns updated file.
+    async fn update_store_policy(
+        &self,
+        id: FileId,
+        store_policy: StorePolicy,
+    ) -> Result<File, Self::Error>;
+
+    /// Replace store policy of all files with sources starting with `prefix`, see
+    /// [`FileSource::as_str`]. Moments when the files become stale are recomputed.
+    ///
+    /// Files are updated atomically: either all or none of them. Returns updated files.
+    async fn update_store_policy_by_source_prefix(
+        &self,
+        prefix: &str,
+        store_policy: StorePolicy,
+    ) -> Result<Vec<File>, Self::Error>;
+
+    /// Record use of the file: set its last used timestamp to `at` and increment its number of
+    /// uses. The moment when the file becomes stale is recomputed.
+    ///
+    /// Returns updated file.
+    async fn mark_used(&self, id: FileId, at: DateTime<Utc>) -> Result<File, Self::Error>;
+
+    /// Pin or unpin the file, see [`FileMetadata::pinned`].
+    ///
+    /// Returns updated file.
+    async fn update_pinned(&self, id: FileId, pinned: bool) -> Result<File, Self::Error>;
 }
 
 #[cfg(test)]
@@ -95,7 +156,15 @@ pub mod mocks {
         #[async_trait]
         impl StorageDatabaseExt for StorageDatabaseExt {
             async fn select_by_source(&self, source: &FileSource) -> Result<Vec<File>, MockStorageDatabaseError>;
+            async fn select_by_uid(&self, uid: Uuid) -> Result<Option<File>, MockStorageDatabaseError>;
+            async fn select_by_source_prefix(&self, prefix: &str) -> Result<Vec<File>, MockStorageDatabaseError>;
+            async fn select_stale(&self, now: DateTime<Utc>) -> Result<Vec<File>, MockStorageDatabaseError>;
             async fn update_status(&self, id: FileId, new_status: FileStatus) -> Result<File, MockStorageDatabaseError>;
+            async fn update_status_if(&self, id: FileId, expected: FileStatus, new_status: FileStatus) -> Result<Option<File>, MockStorageDatabaseError>;
+            async fn update_store_policy(&self, id: FileId, store_policy: StorePolicy) -> Result<File, MockStorageDatabaseError>;
+            async fn update_store_policy_by_source_prefix(&self, prefix: &str, store_policy: StorePolicy) -> Result<Vec<File>, MockStorageDatabaseError>;
+            async fn mark_used(&self, id: FileId, at: DateTime<Utc>) -> Result<File, MockStorageDatabaseError>;
+            async fn update_pinned(&self, id: FileId, pinned: bool) -> Result<File, MockStorageDatabaseError>;
         }
     }
 }
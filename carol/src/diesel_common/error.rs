@@ -7,6 +7,7 @@ pub use diesel::result::{ConnectionError, Error as DieselError};
 pub use diesel_async::pooled_connection::deadpool::{BuildError, PoolError};
 
 use crate::database::StorageDatabaseError;
+use crate::digest::ParseDigestError;
 use crate::error::NonUtf8PathError;
 
 /// Cache database related errors.
@@ -61,6 +62,18 @@ pub enum ConvertStorePolicyError {
     #[error("store policy data is missing")]
     MissingPolicyData,
 
+    /// Stored moment of store policy is out of range of timestamps.
+    #[error("store policy timestamp is out of range")]
+    TimestampOutOfRange,
+
+    /// Failed to parse stored digest.
+    #[error(transparent)]
+    DigestError(#[from] ParseDigestError),
+
+    /// Failed to parse stored globally unique identifier.
+    #[error(transparent)]
+    UidError(#[from] uuid::Error),
+
     #[error("failed to deserialize enum variant: {0}")]
     BadEnumVariat(String),
 }
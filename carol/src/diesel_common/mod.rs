@@ -0,0 +1,18 @@
+//! Types shared by storage databases built with Diesel, i.e. SQLite and PostgreSQL ones.
+
+pub mod error;
+pub(crate) mod models;
+
+/// Pattern of `LIKE` operator matching strings starting with `prefix`, with `\\` as escape
+/// character.
+pub(crate) fn source_prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
@@ -0,0 +1,629 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use diesel::deserialize::FromSqlRow;
+use diesel::sql_types::Integer;
+use diesel::{AsExpression, Queryable};
+use diesel_enum::DbEnum;
+use uuid::Uuid;
+
+use super::error::{ConvertStorePolicyError, CreateNewFileError};
+use crate::file;
+
+/// Row of `files` table. Fields are in the order of the table columns.
+#[derive(Queryable, Clone, Debug, PartialEq, Eq)]
+pub struct File {
+    pub id: i64,
+    pub source: String,
+    pub cache_path: String,
+    pub filename: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub store_policy: StorePolicy,
+    pub store_policy_data: Option<i64>,
+    pub store_policy_extra_data: Option<i64>,
+    pub status: FileStatus,
+    pub validator: Option<String>,
+    pub negative_status: Option<i32>,
+    pub digest: Option<String>,
+    pub uid: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    pub uses: i64,
+}
+
+/// New row of `files` table, without generated primary key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewFile {
+    pub source: String,
+    pub cache_path: String,
+    pub filename: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub store_policy: StorePolicy,
+    pub store_policy_data: Option<i64>,
+    pub store_policy_extra_data: Option<i64>,
+    pub status: FileStatus,
+    pub validator: Option<String>,
+    pub negative_status: Option<i32>,
+    pub digest: Option<String>,
+    pub uid: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    pub uses: i64,
+}
+
+impl TryFrom<file::FileMetadata> for NewFile {
+    type Error = CreateNewFileError;
+
+    fn try_from(metadata: file::FileMetadata) -> Result<Self, Self::Error> {
+        let (store_policy, store_policy_data, store_policy_extra_data) =
+            metadata.store_policy.try_into()?;
+        let expires_at = metadata.expires_at();
+        Ok(Self {
+            source: metadata.source.to_string(),
+            cache_path: metadata
+                .path
+                .as_os_str()
+                .to_str()
+                .ok_or(crate::error::NonUtf8PathError)?
+                .to_string(),
+            filename: metadata.filename,
+            created: metadata.created,
+            last_used: metadata.last_used,
+            store_policy,
+            store_policy_data,
+            store_policy_extra_data,
+            status: file::FileStatus::default().into(),
+            validator: metadata.validator,
+            negative_status: metadata.negative_status.map(i32::from),
+            digest: metadata.digest.map(String::from),
+            uid: Some(Uuid::now_v7().to_string()),
+            expires_at,
+            pinned: metadata.pinned,
+            uses: metadata
+                .uses
+                .try_into()
+                .map_err(ConvertStorePolicyError::from)?,
+        })
+    }
+}
+
+impl TryFrom<File> for file::FileMetadata {
+    type Error = ConvertStorePolicyError;
+
+    fn try_from(file: File) -> Result<Self, Self::Error> {
+        let store_policy = (
+            file.store_policy,
+            file.store_policy_data,
+            file.store_policy_extra_data,
+        )
+            .try_into()?;
+        Ok(file::FileMetadata {
+            source: file::FileSource::parse(&file.source),
+            filename: file.filename,
+            path: PathBuf::from(file.cache_path),
+            store_policy,
+            created: file.created,
+            last_used: file.last_used,
+            validator: file.validator,
+            negative_status: file.negative_status.map(u16::try_from).transpose()?,
+            digest: file.digest.map(|digest| digest.parse()).transpose()?,
+            pinned: file.pinned,
+            uses: file.uses.try_into()?,
+        })
+    }
+}
+
+/// Database mirror type for [`file::StorePolicy`] (when paired with its data as two `i64`),
+/// providing its serialization through integer conversion.
+///
+/// Durations are stored in microseconds and moments as microseconds since Unix epoch, with the
+/// same precision as timestamps, shorter parts are truncated. Numbers of uses are stored as they
+/// are. Extra data is used only by policies with two durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression, DbEnum)]
+#[diesel(sql_type = Integer)]
+#[diesel_enum(error_fn = ConvertStorePolicyError::bad_enum_variant)]
+#[diesel_enum(error_type = ConvertStorePolicyError)]
+#[repr(i32)]
+pub enum StorePolicy {
+    StoreForever = 0,
+    ExpiresAfter = 1,
+    ExpiresAfterNotUsedFor = 2,
+    ExpiresAt = 3,
+    ExpiresAfterOrNotUsedFor = 4,
+    ExpiresAfterUses = 5,
+}
+
+impl TryFrom<file::StorePolicy> for (StorePolicy, Option<i64>, Option<i64>) {
+    type Error = ConvertStorePolicyError;
+
+    fn try_from(value: file::StorePolicy) -> Result<Self, Self::Error> {
+        Ok(match value {
+            file::StorePolicy::StoreForever => (StorePolicy::StoreForever, None, None),
+            file::StorePolicy::ExpiresAfter { duration } => (
+                StorePolicy::ExpiresAfter,
+                Some(duration.as_micros().try_into()?),
+                None,
+            ),
+            file::StorePolicy::ExpiresAfterNotUsedFor { duration } => (
+                StorePolicy::ExpiresAfterNotUsedFor,
+                Some(duration.as_micros().try_into()?),
+                None,
+            ),
+            file::StorePolicy::ExpiresAt { at } => {
+                (StorePolicy::ExpiresAt, Some(at.timestamp_micros()), None)
+            }
+            file::StorePolicy::ExpiresAfterOrNotUsedFor {
+                duration,
+                not_used_for,
+            } => (
+                StorePolicy::ExpiresAfterOrNotUsedFor,
+                Some(duration.as_micros().try_into()?),
+                Some(not_used_for.as_micros().try_into()?),
+            ),
+            file::StorePolicy::ExpiresAfterUses { count } => {
+                (StorePolicy::ExpiresAfterUses, Some(count.try_into()?), None)
+            }
+        })
+    }
+}
+
+impl TryFrom<(StorePolicy, Option<i64>, Option<i64>)> for file::StorePolicy {
+    type Error = ConvertStorePolicyError;
+
+    fn try_from(value: (StorePolicy, Option<i64>, Option<i64>)) -> Result<Self, Self::Error> {
+        use ConvertStorePolicyError::{MissingPolicyData, TimestampOutOfRange};
+        let (policy, data, extra_data) = value;
+        let duration = |data: Option<i64>| -> Result<Duration, ConvertStorePolicyError> {
+            Ok(Duration::from_micros(
+                data.ok_or(MissingPolicyData)?.try_into()?,
+            ))
+        };
+        Ok(match policy {
+            StorePolicy::StoreForever => file::StorePolicy::StoreForever,
+            StorePolicy::ExpiresAfter => file::StorePolicy::ExpiresAfter {
+                duration: duration(data)?,
+            },
+            StorePolicy::ExpiresAfterNotUsedFor => file::StorePolicy::ExpiresAfterNotUsedFor {
+                duration: duration(data)?,
+            },
+            StorePolicy::ExpiresAt => file::StorePolicy::ExpiresAt {
+                at: DateTime::from_timestamp_micros(data.ok_or(MissingPolicyData)?)
+                    .ok_or(TimestampOutOfRange)?,
+            },
+            StorePolicy::ExpiresAfterOrNotUsedFor => file::StorePolicy::ExpiresAfterOrNotUsedFor {
+                duration: duration(data)?,
+                not_used_for: duration(extra_data)?,
+            },
+            StorePolicy::ExpiresAfterUses => file::StorePolicy::ExpiresAfterUses {
+                count: data.ok_or(MissingPolicyData)?.try_into()?,
+            },
+        })
+    }
+}
+
+/// Database mirror type for [`file::FileStatus`],
+/// providing its serialization through integer conversion.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromSqlRow, AsExpression, DbEnum)]
+#[diesel_enum(error_fn = ConvertStorePolicyError::bad_enum_variant)]
+#[diesel_enum(error_type = ConvertStorePolicyError)]
+#[diesel(sql_type = Integer)]
+#[repr(i32)]
+pub enum FileStatus {
+    /// File is not yet fully moved into storage.
+    #[default]
+    Pending = 0,
+
+    /// File is ready to be used.
+    Ready = 1,
+
+    /// File is scheduled for removal.
+    ToRemove = 2,
+
+    /// File is corrupted. This means that something is wrong with the file
+    /// or the cache entry.
+    Corrupted = 3,
+
+    /// File is partially written. Writing failed, but it can be resumed.
+    Partial = 4,
+}
+
+impl From<file::FileStatus> for FileStatus {
+    fn from(value: file::FileStatus) -> Self {
+        match value {
+            file::FileStatus::Pending => Self::Pending,
+            file::FileStatus::Ready => Self::Ready,
+            file::FileStatus::ToRemove => Self::ToRemove,
+            file::FileStatus::Corrupted => Self::Corrupted,
+            file::FileStatus::Partial => Self::Partial,
+        }
+    }
+}
+
+impl From<FileStatus> for file::FileStatus {
+    fn from(value: FileStatus) -> Self {
+        match value {
+            FileStatus::Pending => file::FileStatus::Pending,
+            FileStatus::Ready => file::FileStatus::Ready,
+            FileStatus::ToRemove => file::FileStatus::ToRemove,
+            FileStatus::Corrupted => file::FileStatus::Corrupted,
+            FileStatus::Partial => file::FileStatus::Partial,
+        }
+    }
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        file::FileStatus::from(*self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{Digest, DigestAlgorithm};
+    use rstest::rstest;
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    #[rstest]
+    #[case::valid_metadata(
+        file::FileMetadata {
+            source: file::FileSource::Custom("somesource".to_string()),
+            filename: Some("somename".to_string()),
+            path: PathBuf::from("/some/path"),
+            store_policy: file::StorePolicy::StoreForever,
+            created: DateTime::<Utc>::MAX_UTC,
+            last_used: DateTime::<Utc>::MAX_UTC,
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: false,
+            uses: 0,
+        },
+        "/some/path".to_string(),
+        "somesource".to_string(),
+        StorePolicy::StoreForever,
+        None,
+    )]
+    #[cfg_attr(
+        unix,
+        should_panic(
+            expected = "convert FileMetadata into NewFile: NonUtf8PathError(NonUtf8PathError)"
+        )
+    )]
+    #[cfg_attr(
+        unix,
+        case::non_utf8_path(
+            file::FileMetadata {
+                source: file::FileSource::Custom("somesource".to_string()),
+                filename: Some("somename".to_string()),
+                path: PathBuf::from(OsString::from_vec(vec![255])),
+                store_policy: file::StorePolicy::StoreForever,
+                created: DateTime::<Utc>::MAX_UTC,
+                last_used: DateTime::<Utc>::MAX_UTC,
+                validator: None,
+                negative_status: None,
+                digest: None,
+                pinned: false,
+                uses: 0,
+            },
+            "".to_string(), // there is no valid value, conversion will panic
+            "somesource".to_string(),
+            StorePolicy::StoreForever,
+            None,
+        )
+    )]
+    #[trace]
+    fn test_file_metadata_to_new_file_conversion(
+        #[case] metadata: file::FileMetadata,
+        #[case] expected_path: String,
+        #[case] expected_source: String,
+        #[case] expected_policy: StorePolicy,
+        #[case] expected_policy_data: Option<i64>,
+    ) {
+        let new_file =
+            NewFile::try_from(metadata.clone()).expect("convert FileMetadata into NewFile");
+        assert_eq!(new_file.cache_path, expected_path);
+        assert_eq!(new_file.created, metadata.created);
+        assert_eq!(new_file.filename, metadata.filename);
+        assert_eq!(new_file.last_used, metadata.last_used);
+        assert_eq!(new_file.source, expected_source);
+        assert_eq!(new_file.status, FileStatus::default());
+        assert_eq!(new_file.store_policy, expected_policy);
+        assert_eq!(new_file.store_policy_data, expected_policy_data);
+    }
+
+    #[rstest]
+    #[case::valid_file(
+        File {
+            id: 1,
+            source: "http://localhost:8080/file.txt".to_string(),
+            cache_path: "/some/path".to_string(),
+            filename: Some("file.txt".to_string()),
+            created: DateTime::<Utc>::MAX_UTC,
+            last_used: DateTime::<Utc>::MAX_UTC,
+            store_policy: StorePolicy::StoreForever,
+            store_policy_data: None,
+            store_policy_extra_data: None,
+            status: FileStatus::Pending,
+            validator: None,
+            negative_status: None,
+            digest: None,
+            uid: None,
+            expires_at: None,
+            pinned: false,
+            uses: 0,
+        },
+        PathBuf::from("/some/path"),
+        file::FileSource::Url(url::Url::parse("http://localhost:8080/file.txt").unwrap()),
+        file::StorePolicy::StoreForever,
+    )]
+    #[trace]
+    fn test_file_to_file_metadata_conversion(
+        #[case] file: File,
+        #[case] expected_path: PathBuf,
+        #[case] expected_source: file::FileSource,
+        #[case] expected_policy: file::StorePolicy,
+    ) {
+        let metadata =
+            file::FileMetadata::try_from(file.clone()).expect("convert File into FileMetadata");
+        assert_eq!(metadata.path, expected_path);
+        assert_eq!(metadata.created, file.created);
+        assert_eq!(metadata.filename, file.filename);
+        assert_eq!(metadata.last_used, file.last_used);
+        assert_eq!(metadata.source, expected_source);
+        assert_eq!(metadata.store_policy, expected_policy);
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(404))]
+    #[case(Some(410))]
+    #[trace]
+    fn test_negative_status_conversion(#[case] negative_status: Option<u16>) {
+        let metadata = file::FileMetadata {
+            source: file::FileSource::Custom("somesource".to_string()),
+            filename: None,
+            path: PathBuf::from("/some/path"),
+            store_policy: file::StorePolicy::StoreForever,
+            created: DateTime::<Utc>::MAX_UTC,
+            last_used: DateTime::<Utc>::MAX_UTC,
+            validator: None,
+            negative_status,
+            digest: None,
+            pinned: false,
+            uses: 0,
+        };
+        let new_file = NewFile::try_from(metadata.clone()).expect("convert into NewFile");
+        assert_eq!(new_file.negative_status, negative_status.map(i32::from));
+        let file = File {
+            id: 1,
+            source: new_file.source,
+            cache_path: new_file.cache_path,
+            filename: new_file.filename,
+            created: new_file.created,
+            last_used: new_file.last_used,
+            store_policy: new_file.store_policy,
+            store_policy_data: new_file.store_policy_data,
+            store_policy_extra_data: new_file.store_policy_extra_data,
+            status: new_file.status,
+            validator: new_file.validator,
+            negative_status: new_file.negative_status,
+            digest: new_file.digest,
+            uid: None,
+            expires_at: None,
+            pinned: new_file.pinned,
+            uses: new_file.uses,
+        };
+        let converted = file::FileMetadata::try_from(file).expect("convert into FileMetadata");
+        assert_eq!(converted, metadata);
+    }
+
+    #[rstest]
+    #[case(None, None)]
+    #[case(
+        Some(Digest::compute(DigestAlgorithm::Sha256, "hello world")),
+        Some("sha-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=")
+    )]
+    #[trace]
+    fn test_digest_conversion(#[case] digest: Option<Digest>, #[case] expected: Option<&str>) {
+        let metadata = file::FileMetadata {
+            source: file::FileSource::Custom("somesource".to_string()),
+            filename: None,
+            path: PathBuf::from("/some/path"),
+            store_policy: file::StorePolicy::StoreForever,
+            created: DateTime::<Utc>::MAX_UTC,
+            last_used: DateTime::<Utc>::MAX_UTC,
+            validator: None,
+            negative_status: None,
+            digest,
+            pinned: false,
+            uses: 0,
+        };
+        let new_file = NewFile::try_from(metadata.clone()).expect("convert into NewFile");
+        assert_eq!(new_file.digest.as_deref(), expected);
+        let file = File {
+            id: 1,
+            source: new_file.source,
+            cache_path: new_file.cache_path,
+            filename: new_file.filename,
+            created: new_file.created,
+            last_used: new_file.last_used,
+            store_policy: new_file.store_policy,
+            store_policy_data: new_file.store_policy_data,
+            store_policy_extra_data: new_file.store_policy_extra_data,
+            status: new_file.status,
+            validator: new_file.validator,
+            negative_status: new_file.negative_status,
+            digest: new_file.digest,
+            uid: None,
+            expires_at: None,
+            pinned: new_file.pinned,
+            uses: new_file.uses,
+        };
+        let converted = file::FileMetadata::try_from(file).expect("convert into FileMetadata");
+        assert_eq!(converted, metadata);
+    }
+
+    #[rstest]
+    #[case(file::StorePolicy::StoreForever, StorePolicy::StoreForever, None, None)]
+    #[case(file::StorePolicy::ExpiresAfter { duration: Duration::from_secs(42) }, StorePolicy::ExpiresAfter, Some(42_000_000), None)]
+    #[case(file::StorePolicy::ExpiresAfter { duration: Duration::from_micros(1_500_001) }, StorePolicy::ExpiresAfter, Some(1_500_001), None)]
+    #[case(file::StorePolicy::ExpiresAfter { duration: Duration::from_secs(i32::MAX as u64 + 1) }, StorePolicy::ExpiresAfter, Some((i32::MAX as i64 + 1) * 1_000_000), None)]
+    #[case(file::StorePolicy::ExpiresAfterNotUsedFor { duration: Duration::from_secs(1 << 40) }, StorePolicy::ExpiresAfterNotUsedFor, Some((1 << 40) * 1_000_000), None)]
+    #[case(file::StorePolicy::ExpiresAt { at: DateTime::from_timestamp_micros(1_750_000_000_000_001).unwrap() }, StorePolicy::ExpiresAt, Some(1_750_000_000_000_001), None)]
+    #[case(file::StorePolicy::ExpiresAt { at: DateTime::<Utc>::MIN_UTC }, StorePolicy::ExpiresAt, Some(DateTime::<Utc>::MIN_UTC.timestamp_micros()), None)]
+    #[case(file::StorePolicy::ExpiresAfterOrNotUsedFor { duration: Duration::from_secs(42), not_used_for: Duration::from_micros(7) }, StorePolicy::ExpiresAfterOrNotUsedFor, Some(42_000_000), Some(7))]
+    #[case(file::StorePolicy::ExpiresAfterUses { count: 3 }, StorePolicy::ExpiresAfterUses, Some(3), None)]
+    #[should_panic(expected = "serialize StorePolicy: TryFromIntError")]
+    #[case(file::StorePolicy::ExpiresAfterUses { count: u64::MAX }, StorePolicy::ExpiresAfterUses, Some(42), None)]
+    #[should_panic(expected = "serialize StorePolicy: TryFromIntError")]
+    #[case(file::StorePolicy::ExpiresAfter { duration: Duration::MAX }, StorePolicy::ExpiresAfter, Some(42), None)]
+    #[should_panic(expected = "serialize StorePolicy: TryFromIntError")]
+    #[case(file::StorePolicy::ExpiresAfterNotUsedFor { duration: Duration::MAX }, StorePolicy::ExpiresAfter, Some(42), None)]
+    #[should_panic(expected = "serialize StorePolicy: TryFromIntError")]
+    #[case(file::StorePolicy::ExpiresAfterOrNotUsedFor { duration: Duration::from_secs(42), not_used_for: Duration::MAX }, StorePolicy::ExpiresAfterOrNotUsedFor, Some(42), None)]
+    #[trace]
+    fn test_store_policy_serialization(
+        #[case] input: file::StorePolicy,
+        #[case] expected_policy: StorePolicy,
+        #[case] expected_data: Option<i64>,
+        #[case] expected_extra_data: Option<i64>,
+    ) {
+        let (policy, data, extra_data) = input.try_into().expect("serialize StorePolicy");
+        assert_eq!(policy, expected_policy);
+        assert_eq!(data, expected_data);
+        assert_eq!(extra_data, expected_extra_data);
+    }
+
+    #[rstest]
+    #[case(StorePolicy::StoreForever, None, None, file::StorePolicy::StoreForever)]
+    #[case(
+        StorePolicy::StoreForever,
+        Some(42),
+        Some(42),
+        file::StorePolicy::StoreForever
+    )]
+    #[case(
+        StorePolicy::ExpiresAfter,
+        Some(1_500_001),
+        None,
+        file::StorePolicy::ExpiresAfter { duration: Duration::from_micros(1_500_001) },
+    )]
+    #[case(
+        StorePolicy::ExpiresAt,
+        Some(1_750_000_000_000_001),
+        None,
+        file::StorePolicy::ExpiresAt { at: DateTime::from_timestamp_micros(1_750_000_000_000_001).unwrap() },
+    )]
+    #[case(
+        StorePolicy::ExpiresAfterOrNotUsedFor,
+        Some(42_000_000),
+        Some(7),
+        file::StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(42),
+            not_used_for: Duration::from_micros(7),
+        },
+    )]
+    #[case(
+        StorePolicy::ExpiresAfterUses,
+        Some(3),
+        None,
+        file::StorePolicy::ExpiresAfterUses { count: 3 },
+    )]
+    #[should_panic(expected = "deserialize StorePolicy: MissingPolicyData")]
+    #[case::missing_policy_data(
+        StorePolicy::ExpiresAfter,
+        None,
+        None,
+        file::StorePolicy::ExpiresAfter {
+            duration: Duration::from_secs(42), // there is no valid value, conversion will panic
+        },
+    )]
+    #[should_panic(expected = "deserialize StorePolicy: MissingPolicyData")]
+    #[case::missing_policy_data(
+        StorePolicy::ExpiresAfterNotUsedFor,
+        None,
+        None,
+        file::StorePolicy::ExpiresAfterNotUsedFor {
+            duration: Duration::from_secs(42), // there is no valid value, conversion will panic
+        },
+    )]
+    #[should_panic(expected = "deserialize StorePolicy: MissingPolicyData")]
+    #[case::missing_policy_extra_data(
+        StorePolicy::ExpiresAfterOrNotUsedFor,
+        Some(42),
+        None,
+        file::StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_micros(42), // there is no valid value, conversion will panic
+            not_used_for: Duration::from_micros(42),
+        },
+    )]
+    #[should_panic(expected = "deserialize StorePolicy: TryFromIntError(TryFromIntError(()))")]
+    #[case::negative_policy_data(
+        StorePolicy::ExpiresAfterNotUsedFor,
+        Some(-42),
+        None,
+        file::StorePolicy::ExpiresAfterNotUsedFor {
+            duration: Duration::from_secs(42), // there is no valid value, conversion will panic
+        },
+    )]
+    #[should_panic(expected = "deserialize StorePolicy: TimestampOutOfRange")]
+    #[case::timestamp_out_of_range(
+        StorePolicy::ExpiresAt,
+        Some(i64::MAX),
+        None,
+        file::StorePolicy::ExpiresAt {
+            at: DateTime::<Utc>::MAX_UTC, // there is no valid value, conversion will panic
+        },
+    )]
+    #[trace]
+    fn test_store_policy_deserialization(
+        #[case] policy: StorePolicy,
+        #[case] data: Option<i64>,
+        #[case] extra_data: Option<i64>,
+        #[case] expected_policy: file::StorePolicy,
+    ) {
+        let result = file::StorePolicy::try_from((policy, data, extra_data))
+            .expect("deserialize StorePolicy");
+        assert_eq!(result, expected_policy);
+    }
+
+    #[rstest]
+    #[case(
+        file::StorePolicy::ExpiresAfter { duration: Duration::new(42, 1_234_567) },
+        file::StorePolicy::ExpiresAfter { duration: Duration::new(42, 1_234_000) },
+    )]
+    #[case(
+        file::StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_nanos(999),
+            not_used_for: Duration::from_nanos(1_001),
+        },
+        file::StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::ZERO,
+            not_used_for: Duration::from_micros(1),
+        },
+    )]
+    #[case(
+        file::StorePolicy::ExpiresAt {
+            at: DateTime::from_timestamp(1_750_000_000, 123_456_789).unwrap(),
+        },
+        file::StorePolicy::ExpiresAt {
+            at: DateTime::from_timestamp(1_750_000_000, 123_456_000).unwrap(),
+        },
+    )]
+    #[trace]
+    fn test_store_policy_truncated_to_micros(
+        #[case] input: file::StorePolicy,
+        #[case] expected: file::StorePolicy,
+    ) {
+        let serialized: (StorePolicy, Option<i64>, Option<i64>) =
+            input.try_into().expect("serialize StorePolicy");
+        let result = file::StorePolicy::try_from(serialized).expect("deserialize StorePolicy");
+        assert_eq!(result, expected);
+    }
+}
@@ -0,0 +1,227 @@
+//! Digests of file content.
+
+use std::fmt;
+use std::str::FromStr;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::digest::Digest as _;
+use sha2::{Sha256, Sha512};
+
+/// Hash algorithm of a [`Digest`].
+///
+/// Algorithms are named as in the HTTP Digest Algorithm Values registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DigestAlgorithm {
+    /// MD5. Insecure, but still widely used, e.g. in `Content-MD5` header.
+    Md5,
+
+    /// SHA-256.
+    Sha256,
+
+    /// SHA-512.
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// Return algorithm name, e.g. `sha-256`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha-256",
+            Self::Sha512 => "sha-512",
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = ParseDigestError;
+
+    /// Parse algorithm name. Names are case-insensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Md5, Self::Sha256, Self::Sha512]
+            .into_iter()
+            .find(|algorithm| s.eq_ignore_ascii_case(algorithm.as_str()))
+            .ok_or_else(|| ParseDigestError::UnknownAlgorithm(s.to_string()))
+    }
+}
+
+/// Digest of file content.
+///
+/// Represented as a string in `<algorithm>=<base64 value>` form, e.g.
+/// `sha-256=LCa0a2j/xo/5m0U8HTBBNBNCLXBkg7+g+YpeiGJm564=`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Digest {
+    /// Hash algorithm.
+    pub algorithm: DigestAlgorithm,
+
+    /// Hash value.
+    pub value: Vec<u8>,
+}
+
+impl Digest {
+    /// Create digest computed with `algorithm`.
+    pub fn new(algorithm: DigestAlgorithm, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            algorithm,
+            value: value.into(),
+        }
+    }
+
+    /// Compute digest of `content` with `algorithm`.
+    pub fn compute(algorithm: DigestAlgorithm, content: impl AsRef<[u8]>) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(content.as_ref());
+        hasher.finalize()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}",
+            self.algorithm,
+            BASE64_STANDARD.encode(&self.value)
+        )
+    }
+}
+
+impl FromStr for Digest {
+    type Err = ParseDigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, value) = s.split_once('=').ok_or(ParseDigestError::MissingValue)?;
+        Ok(Self {
+            algorithm: algorithm.parse()?,
+            value: BASE64_STANDARD.decode(value)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Digest {
+    type Error = ParseDigestError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Digest> for String {
+    fn from(value: Digest) -> String {
+        value.to_string()
+    }
+}
+
+/// Error during parsing a [`Digest`].
+#[derive(thiserror::Error, Debug)]
+pub enum ParseDigestError {
+    /// Digest algorithm is not supported.
+    #[error("unknown digest algorithm: {0}")]
+    UnknownAlgorithm(String),
+
+    /// Digest has no value.
+    #[error("digest value is missing")]
+    MissingValue,
+
+    /// Digest value is not valid base64.
+    #[error("invalid digest value")]
+    InvalidValue(#[from] base64::DecodeError),
+}
+
+/// Incremental computation of a [`Digest`].
+pub(crate) enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Md5 => Self::Md5(Md5::new()),
+            DigestAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        match self {
+            Self::Md5(hasher) => Digest::new(DigestAlgorithm::Md5, hasher.finalize().as_slice()),
+            Self::Sha256(hasher) => {
+                Digest::new(DigestAlgorithm::Sha256, hasher.finalize().as_slice())
+            }
+            Self::Sha512(hasher) => {
+                Digest::new(DigestAlgorithm::Sha512, hasher.finalize().as_slice())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Digest, DigestAlgorithm, Hasher};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(DigestAlgorithm::Md5, "md5=XrY7u+Ae7tCTyyK7j1rNww==")]
+    #[case(
+        DigestAlgorithm::Sha256,
+        "sha-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+    )]
+    #[case(
+        DigestAlgorithm::Sha512,
+        "sha-512=MJ7MSJwS1utMxA9QyQLytNDtd+5RGnx6m808qG1M2G+YndNbxf9JlnDaNCVbRbDP2DDoH2Bdz33FVC6TrpzXbw=="
+    )]
+    #[trace]
+    fn test_compute(#[case] algorithm: DigestAlgorithm, #[case] expected: &str) {
+        let digest = Digest::compute(algorithm, "hello world");
+        assert_eq!(digest.to_string(), expected);
+        assert_eq!(expected.parse::<Digest>().unwrap(), digest);
+    }
+
+    #[test]
+    fn test_hasher_chunks() {
+        let mut hasher = Hasher::new(DigestAlgorithm::Sha256);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(
+            hasher.finalize(),
+            Digest::compute(DigestAlgorithm::Sha256, "hello world")
+        );
+    }
+
+    #[rstest]
+    #[case("SHA-256", Some(DigestAlgorithm::Sha256))]
+    #[case("md5", Some(DigestAlgorithm::Md5))]
+    #[case("sha", None)]
+    #[trace]
+    fn test_parse_algorithm(#[case] input: &str, #[case] expected: Option<DigestAlgorithm>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+
+    #[rstest]
+    #[case("sha-256")]
+    #[case("crc32c=AAAAAA==")]
+    #[case("md5=not base64")]
+    #[trace]
+    fn test_parse_invalid(#[case] input: &str) {
+        assert!(input.parse::<Digest>().is_err());
+    }
+}
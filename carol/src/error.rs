@@ -2,6 +2,7 @@ use std::error::Error as StdError;
 use std::io::Error as IoError;
 
 use crate::database::StorageDatabaseError;
+use crate::digest::Digest;
 
 /// Non UTF-8 symbol in path.
 #[derive(thiserror::Error, Debug)]
@@ -27,21 +28,40 @@ pub enum StorageError<E: StorageDatabaseError> {
     #[error("awaited file failed to download")]
     AwaitingError,
 
+    /// File can't be resumed, because it is not partially stored.
+    ///
+    /// This happens when the file is already complete, removed, or being resumed by another
+    /// thread.
+    #[error("file is not partially stored")]
+    NotResumable,
+
+    /// There are no mirrors to fetch the file from.
+    #[error("no mirrors to fetch the file from")]
+    NoMirrors,
+
+    /// Content of the file does not match its expected digest.
+    #[error("digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: Digest, actual: Digest },
+
     #[error(transparent)]
     NonUtf8PathError(#[from] NonUtf8PathError),
 
     #[error(transparent)]
-    CustomError(Box<dyn StdError + Send + 'static>),
+    CustomError(Box<dyn StdError + Send + Sync + 'static>),
 
     #[error("storage directory does not exist")]
     StorageDirectoryDoesNotExist,
 
     #[error("storage directory path is not absolute")]
     StorageDirectoryPathIsNotAbsolute,
+
+    /// Required parameter of [`StorageManagerBuilder`](crate::StorageManagerBuilder) is missing.
+    #[error("storage manager builder is missing {0}")]
+    IncompleteBuilder(&'static str),
 }
 
 impl<E: StorageDatabaseError> StorageError<E> {
-    pub fn custom<T: StdError + 'static + Send>(error: T) -> Self {
+    pub fn custom<T: StdError + 'static + Send + Sync>(error: T) -> Self {
         Self::CustomError(Box::new(error))
     }
 }
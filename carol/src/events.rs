@@ -0,0 +1,176 @@
+//! Notifications about changes in storage.
+
+use futures_util::Stream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::file::{File, FileId, FileSource, FileStatus, StorePolicy};
+
+/// Number of events kept for slow subscribers before they start missing events.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Change in storage, see [`StorageManager::subscribe`](crate::StorageManager::subscribe).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageEvent {
+    /// New file entry was added to storage. Its content is being written.
+    FileAdded {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+    },
+
+    /// File was completely written and became [`FileStatus::Ready`].
+    FileReady(Box<File>),
+
+    /// File was removed from storage.
+    FileRemoved {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+    },
+
+    /// Stale file was removed from storage, see
+    /// [`StorageManager::evict_stale`](crate::StorageManager::evict_stale).
+    ///
+    /// [`StorageEvent::FileRemoved`] is not emitted for evicted files.
+    FileEvicted {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+    },
+
+    /// Content of the file was found corrupted, e.g. it doesn't match expected digest.
+    FileCorrupted {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+    },
+
+    /// Status of the file changed to a status without a dedicated event, e.g.
+    /// [`FileStatus::Partial`].
+    StatusChanged {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+        /// New status of the file.
+        status: FileStatus,
+    },
+
+    /// Store policy of the file was changed, e.g. with
+    /// [`StorageManager::set_policy`](crate::StorageManager::set_policy).
+    PolicyChanged {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+        /// New store policy of the file.
+        store_policy: StorePolicy,
+    },
+
+    /// File was pinned or unpinned, see
+    /// [`StorageManager::set_pinned`](crate::StorageManager::set_pinned).
+    PinnedChanged {
+        /// ID of the file.
+        id: FileId,
+        /// Source of the file.
+        source: FileSource,
+        /// Whether the file is pinned now.
+        pinned: bool,
+    },
+
+    /// Subscriber didn't keep up with the changes and `missed` events were dropped.
+    ///
+    /// Any state derived from the events should be reloaded.
+    Lagged {
+        /// Number of dropped events.
+        missed: u64,
+    },
+}
+
+/// Broadcast of storage events to subscribers, shared between clones of storage manager.
+#[derive(Clone, Debug)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<StorageEvent>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::Sender::new(EVENTS_CAPACITY),
+        }
+    }
+}
+
+impl Events {
+    /// Send `event` to all current subscribers.
+    pub fn emit(&self, event: StorageEvent) {
+        // Having no subscribers is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Stream of events emitted after this call.
+    ///
+    /// The stream ends when all storage managers sharing these events are dropped.
+    pub fn subscribe(&self) -> impl Stream<Item = StorageEvent> + Send + 'static {
+        futures_util::stream::unfold(self.sender.subscribe(), async |mut receiver| {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => StorageEvent::Lagged { missed },
+                Err(RecvError::Closed) => return None,
+            };
+            Some((event, receiver))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, StreamExt};
+
+    use super::{Events, StorageEvent, EVENTS_CAPACITY};
+    use crate::file::{FileId, FileSource, FileStatus};
+
+    fn event(id: i64) -> StorageEvent {
+        StorageEvent::StatusChanged {
+            id: FileId::from(id),
+            source: FileSource::Custom("somesource".to_string()),
+            status: FileStatus::Partial,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let events = Events::default();
+        // Nobody is notified
+        events.emit(event(0));
+
+        let mut first = Box::pin(events.subscribe());
+        let mut second = Box::pin(events.clone().subscribe());
+        events.emit(event(1));
+        assert_eq!(first.next().await, Some(event(1)));
+        assert_eq!(second.next().await, Some(event(1)));
+        assert!(first.next().now_or_never().is_none());
+
+        drop(events);
+        assert_eq!(first.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lagged() {
+        let events = Events::default();
+        let mut stream = Box::pin(events.subscribe());
+        for id in 0..EVENTS_CAPACITY as i64 + 2 {
+            events.emit(event(id));
+        }
+        assert_eq!(
+            stream.next().await,
+            Some(StorageEvent::Lagged { missed: 2 })
+        );
+        assert_eq!(stream.next().await, Some(event(2)));
+    }
+}
@@ -8,19 +8,22 @@ use std::time::Duration;
 use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use url::Url;
+use uuid::Uuid;
+
+use crate::digest::Digest;
 
 /// File identifier.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
-pub struct FileId(i32);
+pub struct FileId(i64);
 
-impl From<i32> for FileId {
-    fn from(value: i32) -> Self {
+impl From<i64> for FileId {
+    fn from(value: i64) -> Self {
         Self(value)
     }
 }
 
-impl From<FileId> for i32 {
+impl From<FileId> for i64 {
     fn from(value: FileId) -> Self {
         value.0
     }
@@ -33,7 +36,7 @@ impl fmt::Display for FileId {
 }
 
 /// File source.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FileSource {
     /// File was fetched from given URL.
     Url(Url),
@@ -124,6 +127,9 @@ pub enum FileStatus {
     /// File is scheduled for removal.
     ToRemove,
 
+    /// File is partially written. Writing failed, but it can be resumed.
+    Partial,
+
     /// File is corrupted. This means that something is wrong with the file
     /// or the cache entry.
     Corrupted,
@@ -139,6 +145,7 @@ impl fmt::Display for FileStatus {
                 Self::Pending => "Pending",
                 Self::ToRemove => "ToRemove",
                 Self::Corrupted => "Corrupted",
+                Self::Partial => "Partial",
             }
         )
     }
@@ -151,6 +158,10 @@ impl fmt::Display for FileStatus {
 /// It means that it no longer should be used. User may remove or update it.
 ///
 /// If maintenance server is running, it may remove "stale" files automatically.
+///
+/// SQLite and PostgreSQL databases store durations and moments of policies in microseconds, so
+/// policies read from them have shorter parts truncated. Other databases of this crate keep
+/// policies as they are.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub enum StorePolicy {
     /// File will never be removed. Default policy.
@@ -165,6 +176,26 @@ pub enum StorePolicy {
     /// When this policy is used, [`FileMetadata::last_used`] timestamp is used to define
     /// if the file is stale.
     ExpiresAfterNotUsedFor { duration: Duration },
+
+    /// File is considered stale at a certain moment.
+    ExpiresAt { at: DateTime<Utc> },
+
+    /// File is considered stale after a certain time period after creation or after not being
+    /// used for another period of time, whichever comes first.
+    ExpiresAfterOrNotUsedFor {
+        duration: Duration,
+        not_used_for: Duration,
+    },
+
+    /// File is considered stale after being used a certain number of times.
+    ///
+    /// When this policy is used, [`FileMetadata::uses`] counter is used to define if the file is
+    /// stale. The file becomes stale at the moment of its last use, see
+    /// [`StorageManager::use_file`](crate::StorageManager::use_file).
+    ///
+    /// With zero `count` the file is stale as soon as it is stored, like with zero
+    /// [`StorePolicy::ExpiresAfter`] duration.
+    ExpiresAfterUses { count: u64 },
 }
 
 /// File metadata.
@@ -189,23 +220,99 @@ pub struct FileMetadata {
 
     /// Last used timestamp.
     pub last_used: DateTime<Utc>,
+
+    /// Opaque identifier of the source content version, e.g. HTTP `ETag`.
+    ///
+    /// Used to check that resumed writing of a partially stored file continues the same content.
+    pub validator: Option<String>,
+
+    /// Status code of a negative result, e.g. HTTP `404` if the source does not exist.
+    ///
+    /// Files recording negative results have no content.
+    pub negative_status: Option<u16>,
+
+    /// Expected digest of the file content, e.g. from HTTP `Repr-Digest`.
+    ///
+    /// Content is verified against it before the file becomes ready.
+    pub digest: Option<Digest>,
+
+    /// Pinned file is never considered expired, even if it is stale according to its store
+    /// policy, so it is exempt from eviction.
+    ///
+    /// See [`StorageManager::add_pinned_file_from_stream`] and [`StorageManager::set_pinned`].
+    ///
+    /// [`StorageManager::add_pinned_file_from_stream`]: crate::StorageManager::add_pinned_file_from_stream
+    /// [`StorageManager::set_pinned`]: crate::StorageManager::set_pinned
+    #[serde(default)]
+    pub pinned: bool,
+
+    /// Number of times the file was used, see [`StorageManager::use_file`].
+    ///
+    /// [`StorageManager::use_file`]: crate::StorageManager::use_file
+    #[serde(default)]
+    pub uses: u64,
 }
 
 impl FileMetadata {
-    pub fn time_to_live(&self, now: DateTime<Utc>) -> Option<TimeDelta> {
+    /// Moment when the file becomes stale according to its store policy.
+    ///
+    /// Returns `None` if the file never becomes stale, including the moments too far in the future
+    /// to be represented.
+    ///
+    /// Does not depend on [`FileMetadata::pinned`].
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        fn after(since: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+            since.checked_add_signed(TimeDelta::from_std(duration).ok()?)
+        }
+
         match self.store_policy {
             StorePolicy::StoreForever => None,
-            StorePolicy::ExpiresAfter { duration } => Some(self.created + duration - now),
-            StorePolicy::ExpiresAfterNotUsedFor { duration } => {
-                Some(self.last_used + duration - now)
+            StorePolicy::ExpiresAfter { duration } => after(self.created, duration),
+            StorePolicy::ExpiresAfterNotUsedFor { duration } => after(self.last_used, duration),
+            StorePolicy::ExpiresAt { at } => Some(at),
+            StorePolicy::ExpiresAfterOrNotUsedFor {
+                duration,
+                not_used_for,
+            } => {
+                match (
+                    after(self.created, duration),
+                    after(self.last_used, not_used_for),
+                ) {
+                    (Some(created), Some(last_used)) => Some(created.min(last_used)),
+                    (created, last_used) => created.or(last_used),
+                }
+            }
+            StorePolicy::ExpiresAfterUses { count } => {
+                (self.uses >= count).then_some(self.last_used)
             }
         }
     }
 
+    /// Time left until the file becomes stale.
+    ///
+    /// Returns `None` if the file never becomes stale or is pinned.
+    pub fn time_to_live(&self, now: DateTime<Utc>) -> Option<TimeDelta> {
+        if self.pinned {
+            return None;
+        }
+        self.expires_at().map(|expires_at| expires_at - now)
+    }
+
     pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
         self.time_to_live(now)
             .is_some_and(|delta| delta.num_seconds() <= 0)
     }
+
+    /// Check if the file is stale at `now` exactly and is not pinned.
+    ///
+    /// Used by databases, which can't select stale files by indexed `expires_at`, see
+    /// [`StorageDatabaseExt::select_stale`](crate::StorageDatabaseExt::select_stale).
+    pub(crate) fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        !self.pinned
+            && self
+                .expires_at()
+                .is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 /// Stored file.
@@ -217,6 +324,13 @@ pub struct File {
     /// Unique file identifier.
     pub id: FileId,
 
+    /// Globally unique file identifier, e.g. to reference the file across replicas.
+    ///
+    /// Assigned by the database when the file is stored. Files stored in SQLite databases before
+    /// these identifiers were introduced get one when the database is migrated. Databases
+    /// implemented outside of this crate may still return files without one.
+    pub uid: Option<Uuid>,
+
     /// Status of stored file.
     pub status: FileStatus,
 
@@ -317,6 +431,45 @@ mod tests {
         StorePolicy::ExpiresAfterNotUsedFor { duration: Duration::from_secs(1) },
         Some(TimeDelta::seconds(-1)),
     )]
+    #[case::unrepresentable(
+        now,
+        now,
+        StorePolicy::ExpiresAfter { duration: Duration::MAX },
+        None,
+    )]
+    #[case::expires_at(
+        DateTime::<Utc>::MIN_UTC,
+        DateTime::<Utc>::MIN_UTC,
+        StorePolicy::ExpiresAt { at: now + TimeDelta::seconds(1) },
+        Some(TimeDelta::seconds(1)),
+    )]
+    #[case::created_first(
+        now - TimeDelta::seconds(2),
+        now,
+        StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(3),
+            not_used_for: Duration::from_secs(2),
+        },
+        Some(TimeDelta::seconds(1)),
+    )]
+    #[case::last_used_first(
+        now,
+        now - TimeDelta::seconds(2),
+        StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(2),
+            not_used_for: Duration::from_secs(3),
+        },
+        Some(TimeDelta::seconds(1)),
+    )]
+    #[case::last_used_unrepresentable(
+        now,
+        now,
+        StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(1),
+            not_used_for: Duration::MAX,
+        },
+        Some(TimeDelta::seconds(1)),
+    )]
     #[trace]
     fn test_file_time_to_live(
         now: DateTime<Utc>,
@@ -332,6 +485,11 @@ mod tests {
             store_policy,
             created,
             last_used,
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: false,
+            uses: 0,
         };
         let ttl = file.time_to_live(now);
         assert_eq!(ttl, expected);
@@ -368,6 +526,21 @@ mod tests {
         StorePolicy::ExpiresAfterNotUsedFor { duration: Duration::from_secs(1) },
         true,
     )]
+    #[case( // expired a sec ago
+        DateTime::<Utc>::MIN_UTC,
+        DateTime::<Utc>::MIN_UTC,
+        StorePolicy::ExpiresAt { at: now - TimeDelta::seconds(1) },
+        true,
+    )]
+    #[case( // used now, but created 2 secs ago and lived for 1 sec after that
+        now - TimeDelta::seconds(2),
+        now,
+        StorePolicy::ExpiresAfterOrNotUsedFor {
+            duration: Duration::from_secs(1),
+            not_used_for: Duration::from_secs(1),
+        },
+        true,
+    )]
     #[trace]
     fn test_file_is_expired(
         now: DateTime<Utc>,
@@ -383,8 +556,65 @@ mod tests {
             store_policy,
             created,
             last_used,
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: false,
+            uses: 0,
         };
         let expired = file.is_expired(now);
         assert_eq!(expired, expected);
     }
+
+    #[rstest]
+    #[case(StorePolicy::StoreForever)]
+    #[case(StorePolicy::ExpiresAfter { duration: Duration::from_secs(1) })]
+    #[case(StorePolicy::ExpiresAt { at: DateTime::<Utc>::MIN_UTC })]
+    #[trace]
+    fn test_pinned_file_is_not_expired(now: DateTime<Utc>, #[case] store_policy: StorePolicy) {
+        let file = FileMetadata {
+            source: FileSource::Custom("".to_string()),
+            filename: None,
+            path: PathBuf::from(""),
+            store_policy,
+            created: DateTime::<Utc>::MIN_UTC,
+            last_used: DateTime::<Utc>::MIN_UTC,
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: true,
+            uses: 0,
+        };
+        assert_eq!(file.time_to_live(now), None);
+        assert!(!file.is_expired(now));
+    }
+
+    #[rstest]
+    #[case::unused(0, 1, None)]
+    #[case::used_up(1, 1, Some(TimeDelta::seconds(-1)))]
+    #[case::overused(3, 2, Some(TimeDelta::seconds(-1)))]
+    #[case::partially_used(1, 2, None)]
+    #[trace]
+    fn test_file_expires_after_uses(
+        now: DateTime<Utc>,
+        #[case] uses: u64,
+        #[case] count: u64,
+        #[case] expected: Option<TimeDelta>,
+    ) {
+        let file = FileMetadata {
+            source: FileSource::Custom("".to_string()),
+            filename: None,
+            path: PathBuf::from(""),
+            store_policy: StorePolicy::ExpiresAfterUses { count },
+            created: DateTime::<Utc>::MIN_UTC,
+            last_used: now - TimeDelta::seconds(1),
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: false,
+            uses,
+        };
+        assert_eq!(file.time_to_live(now), expected);
+        assert_eq!(file.is_expired(now), expected.is_some());
+    }
 }
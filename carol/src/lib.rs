@@ -2,7 +2,14 @@
 //!
 //! This library implements an asynchronous managed storage of files in the filesystem. Storage
 //! files directory is paired with a database, which holds files additional metadata. It works with
-//! any filesystem supported by Rust and currently backed by serverless SQLite database.
+//! any filesystem supported by Rust and backed by serverless SQLite database by default.
+//!
+//! With `postgres` feature, PostgreSQL database can be used instead (see `postgres` module), so
+//! storage managers on several hosts can share the storage. With `redb` feature, single-process
+//! deployments can use embedded pure-Rust database instead (see `redb` module). For ephemeral
+//! storages and tests, metadata can be kept in memory with [`memory::MemoryStorageDatabase`].
+//! Custom storage databases can be checked to behave like the provided ones with the conformance
+//! suite of `conformance` feature (see `conformance` module).
 //!
 //! ## How it works
 //!
@@ -13,7 +20,9 @@
 //!   their updates.
 //!
 //! To interact with storage use [`StorageManager`] instance. Before initializing a manager, create
-//! storage directory first. Manager **will not** do this.
+//! storage directory first. Manager **will not** do this, unless it is built with
+//! [`StorageManager::builder`] and [`StorageManagerBuilder::create_dir`]. The builder also accepts
+//! any storage database, including custom implementations of [`StorageDatabase`].
 //!
 //! ## Example
 //!
@@ -48,24 +57,48 @@
 //! # })
 //! ```
 
+mod builder;
 mod database;
+mod diesel_common;
+mod digest;
 mod error;
+mod events;
 mod file;
+mod limits;
+mod prefetch;
 mod storage_config;
 mod storage_manager;
+mod writes;
 
 // Public modules
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redb")]
+pub mod redb;
 pub mod sqlite;
 
 // Public re-exports
+pub use builder::StorageManagerBuilder;
 pub use database::{StorageDatabase, StorageDatabaseError, StorageDatabaseExt};
+pub use digest::{Digest, DigestAlgorithm, ParseDigestError};
 pub use error::{NonUtf8PathError, StorageError};
+pub use events::StorageEvent;
 pub use file::{File, FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
+pub use prefetch::{
+    PrefetchConfig, PrefetchOutcome, PrefetchProgress, PrefetchQueue, PrefetchRequest,
+    PrefetchResult,
+};
 pub use storage_config::{EvictionPolicy, StorageConfig};
 pub use storage_manager::StorageManager;
+pub use writes::WriteProgress;
 
 // Re-exports of extern crates containing types referenced in public API
 #[doc(no_inline)]
 pub use chrono;
 #[doc(no_inline)]
 pub use url;
+#[doc(no_inline)]
+pub use uuid;
@@ -0,0 +1,191 @@
+//! Limits of concurrency and bandwidth of writes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{self, Instant};
+
+use crate::storage_config::StorageConfig;
+
+/// Limits of writes made by storage manager, shared between its clones.
+///
+/// Writes are started in order of their requests. Bandwidth is split equally between running
+/// writes, so a large write can't hold back smaller ones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteLimits {
+    /// Permits of simultaneous writes.
+    writes: Option<Arc<Semaphore>>,
+    /// Bandwidth shared by running writes.
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+impl WriteLimits {
+    /// Create limits from storage configuration.
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            writes: config
+                .max_concurrent_writes
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            bandwidth: config.max_write_bandwidth.map(|rate| {
+                Arc::new(Bandwidth {
+                    rate: rate.max(1),
+                    writes: AtomicUsize::new(0),
+                })
+            }),
+        }
+    }
+
+    /// Wait for a permit to start a write. The write ends when returned permit is dropped.
+    pub async fn start_write(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.writes {
+            // Semaphore is never closed
+            Some(writes) => Some(writes.acquire().await.expect("semaphore is not closed")),
+            None => None,
+        }
+    }
+
+    /// Take a share of bandwidth for a write. The share is returned when it's dropped.
+    pub fn share_bandwidth(&self) -> BandwidthShare {
+        let bucket = self.bandwidth.as_ref().map(|bandwidth| {
+            let writes = bandwidth.writes.fetch_add(1, Ordering::Relaxed) + 1;
+            TokenBucket {
+                bandwidth: bandwidth.clone(),
+                tokens: bandwidth.rate as f64 / writes as f64,
+                refilled: Instant::now(),
+            }
+        });
+        BandwidthShare { bucket }
+    }
+}
+
+/// Bandwidth in bytes per second, split between writes sharing it.
+#[derive(Debug)]
+struct Bandwidth {
+    rate: u64,
+    /// Number of writes sharing the bandwidth.
+    writes: AtomicUsize,
+}
+
+/// Share of bandwidth held by a single write.
+#[derive(Debug)]
+pub(crate) struct BandwidthShare {
+    /// Bucket of bytes allowed to be written, if bandwidth is limited.
+    bucket: Option<TokenBucket>,
+}
+
+/// Token bucket refilled with an equal share of bandwidth per second, holding at most one second
+/// worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    bandwidth: Arc<Bandwidth>,
+    /// Available tokens. Negative if tokens are taken in advance.
+    tokens: f64,
+    /// Time of the last refill.
+    refilled: Instant,
+}
+
+impl BandwidthShare {
+    /// Wait until writing of `len` bytes is allowed by the share of bandwidth.
+    ///
+    /// `len` may exceed the capacity of the bucket, in which case the write waits until the
+    /// tokens are refilled.
+    pub async fn throttle(&mut self, len: usize) {
+        let Some(bucket) = &mut self.bucket else {
+            return;
+        };
+        // Share is recalculated on each call, as writes start and end
+        let writes = bucket.bandwidth.writes.load(Ordering::Relaxed).max(1);
+        let rate = bucket.bandwidth.rate as f64 / writes as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - len as f64;
+        bucket.refilled = now;
+        if bucket.tokens < 0.0 {
+            time::sleep(Duration::from_secs_f64(-bucket.tokens / rate)).await;
+        }
+    }
+}
+
+impl Drop for BandwidthShare {
+    fn drop(&mut self) {
+        if let Some(bucket) = &self.bucket {
+            bucket.bandwidth.writes.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+    use tokio::time::Instant;
+
+    use super::WriteLimits;
+    use crate::storage_config::StorageConfig;
+
+    #[test]
+    fn test_unlimited() {
+        let limits = WriteLimits::default();
+        assert!(limits.start_write().now_or_never().unwrap().is_none());
+        let mut share = limits.share_bandwidth();
+        assert!(share.throttle(usize::MAX).now_or_never().is_some());
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let limits = WriteLimits::new(&StorageConfig {
+            max_concurrent_writes: Some(2),
+            ..Default::default()
+        });
+        let first = limits.start_write().now_or_never().unwrap();
+        let _second = limits.start_write().now_or_never().unwrap();
+        let mut third = Box::pin(limits.start_write());
+        assert!((&mut third).now_or_never().is_none());
+        drop(first);
+        assert!(third.now_or_never().unwrap().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth() {
+        let limits = WriteLimits::new(&StorageConfig {
+            max_write_bandwidth: Some(100),
+            ..Default::default()
+        });
+        let mut share = limits.share_bandwidth();
+        let start = Instant::now();
+        // Full bucket is available immediately
+        share.throttle(100).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        share.throttle(50).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        // Chunks larger than the bucket are allowed after waiting for them
+        share.throttle(200).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(2500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_shared() {
+        let limits = WriteLimits::new(&StorageConfig {
+            max_write_bandwidth: Some(100),
+            ..Default::default()
+        });
+        let mut large = limits.share_bandwidth();
+        let mut small = limits.share_bandwidth();
+        let start = Instant::now();
+        // Large write waits for its own share only
+        let large_write = tokio::spawn(async move { large.throttle(1000).await });
+        tokio::task::yield_now().await;
+        small.throttle(50).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        small.throttle(50).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        large_write.await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(19));
+        // Bandwidth is no longer shared when other write ends
+        small.throttle(200).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+    }
+}
@@ -0,0 +1,319 @@
+//! Implementation of storage database kept in memory of the process.
+//!
+//! Useful for ephemeral caches, which don't need to survive restarts, and for testing code
+//! working with storage manager.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::database::{StorageDatabase, StorageDatabaseError, StorageDatabaseExt};
+use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
+
+/// Counter of databases created by this process, used to make their URIs unique.
+static DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Error of in-memory database operations.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MemoryDatabaseError {
+    /// File with the same source or path already exists.
+    #[error("file with the same source or path already exists")]
+    UniqueViolation,
+
+    /// File with given ID does not exist.
+    #[error("file not found")]
+    NotFound,
+}
+
+impl StorageDatabaseError for MemoryDatabaseError {
+    fn is_unique_violation(&self) -> bool {
+        *self == Self::UniqueViolation
+    }
+
+    fn is_not_found(&self) -> bool {
+        *self == Self::NotFound
+    }
+}
+
+/// Storage database kept in memory.
+///
+/// Clones of the database share the same files. Sources and paths of files are unique, like in
+/// other databases. Files are lost when the last clone is dropped.
+#[derive(Clone)]
+pub struct MemoryStorageDatabase {
+    uri: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<FileId, File>,
+    /// IDs of files by their sources, ordered to select them by prefix.
+    sources: BTreeMap<String, FileId>,
+    /// IDs of files by their paths.
+    paths: HashMap<PathBuf, FileId>,
+    /// IDs of files by their globally unique identifiers.
+    uids: HashMap<Uuid, FileId>,
+    last_id: i64,
+}
+
+impl State {
+    /// Files with sources starting with `prefix`.
+    fn ids_by_source_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = FileId> + 'a {
+        self.sources
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(source, _)| source.starts_with(prefix))
+            .map(|(_, id)| *id)
+    }
+}
+
+impl MemoryStorageDatabase {
+    /// Create new empty database.
+    pub fn new() -> Self {
+        Self {
+            uri: format!("memory://{}", DATABASES.fetch_add(1, Ordering::Relaxed)),
+            state: Default::default(),
+        }
+    }
+}
+
+impl Default for MemoryStorageDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MemoryStorageDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStorageDatabase")
+            .field("uri", &self.uri)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl StorageDatabase for MemoryStorageDatabase {
+    type Uri = String;
+
+    type Error = MemoryDatabaseError;
+
+    fn uri(&self) -> String {
+        self.uri.clone()
+    }
+
+    async fn store(&self, metadata: FileMetadata) -> Result<FileId, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.sources.contains_key(metadata.source.as_str())
+            || state.paths.contains_key(&metadata.path)
+        {
+            return Err(MemoryDatabaseError::UniqueViolation);
+        }
+        state.last_id += 1;
+        let id = FileId::from(state.last_id);
+        let uid = Uuid::now_v7();
+        state
+            .sources
+            .insert(metadata.source.as_str().to_string(), id);
+        state.paths.insert(metadata.path.clone(), id);
+        state.uids.insert(uid, id);
+        let file = File {
+            database: self.uri.clone(),
+            id,
+            uid: Some(uid),
+            status: FileStatus::default(),
+            metadata,
+        };
+        state.files.insert(id, file);
+        Ok(id)
+    }
+
+    async fn get(&self, id: FileId) -> Result<File, Self::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .files
+            .get(&id)
+            .cloned()
+            .ok_or(MemoryDatabaseError::NotFound)
+    }
+
+    async fn remove(&self, id: FileId) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = state.files.remove(&id) {
+            state.sources.remove(file.metadata.source.as_str());
+            state.paths.remove(&file.metadata.path);
+            if let Some(uid) = file.uid {
+                state.uids.remove(&uid);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageDatabaseExt for MemoryStorageDatabase {
+    async fn select_by_source(&self, source: &FileSource) -> Result<Vec<File>, Self::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .sources
+            .get(source.as_str())
+            .map(|id| state.files[id].clone())
+            .into_iter()
+            .collect())
+    }
+
+    async fn select_by_uid(&self, uid: Uuid) -> Result<Option<File>, Self::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.uids.get(&uid).map(|id| state.files[id].clone()))
+    }
+
+    async fn select_by_source_prefix(&self, prefix: &str) -> Result<Vec<File>, Self::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .ids_by_source_prefix(prefix)
+            .map(|id| state.files[&id].clone())
+            .collect())
+    }
+
+    async fn select_stale(&self, now: DateTime<Utc>) -> Result<Vec<File>, Self::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .files
+            .values()
+            .filter(|file| file.metadata.is_stale(now))
+            .cloned()
+            .collect())
+    }
+
+    async fn update_status(&self, id: FileId, new_status: FileStatus) -> Result<File, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get_mut(&id)
+            .ok_or(MemoryDatabaseError::NotFound)?;
+        file.status = new_status;
+        Ok(file.clone())
+    }
+
+    async fn update_status_if(
+        &self,
+        id: FileId,
+        expected: FileStatus,
+        new_status: FileStatus,
+    ) -> Result<Option<File>, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state
+            .files
+            .get_mut(&id)
+            .filter(|file| file.status == expected)
+            .map(|file| {
+                file.status = new_status;
+                file.clone()
+            }))
+    }
+
+    async fn update_store_policy(
+        &self,
+        id: FileId,
+        store_policy: StorePolicy,
+    ) -> Result<File, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get_mut(&id)
+            .ok_or(MemoryDatabaseError::NotFound)?;
+        file.metadata.store_policy = store_policy;
+        Ok(file.clone())
+    }
+
+    async fn update_store_policy_by_source_prefix(
+        &self,
+        prefix: &str,
+        store_policy: StorePolicy,
+    ) -> Result<Vec<File>, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<_> = state.ids_by_source_prefix(prefix).collect();
+        Ok(ids
+            .into_iter()
+            .map(|id| {
+                let file = state.files.get_mut(&id).expect("indexed file exists");
+                file.metadata.store_policy = store_policy;
+                file.clone()
+            })
+            .collect())
+    }
+
+    async fn mark_used(&self, id: FileId, at: DateTime<Utc>) -> Result<File, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get_mut(&id)
+            .ok_or(MemoryDatabaseError::NotFound)?;
+        file.metadata.last_used = at;
+        file.metadata.uses += 1;
+        Ok(file.clone())
+    }
+
+    async fn update_pinned(&self, id: FileId, pinned: bool) -> Result<File, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get_mut(&id)
+            .ok_or(MemoryDatabaseError::NotFound)?;
+        file.metadata.pinned = pinned;
+        Ok(file.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorageDatabase;
+    use crate::database::{StorageDatabase, StorageDatabaseError};
+    use crate::file::{FileMetadata, FileSource, StorePolicy};
+    use chrono::Utc;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::conformance::run(&MemoryStorageDatabase::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_files() {
+        let database = MemoryStorageDatabase::new();
+        let id = database
+            .store(FileMetadata {
+                source: FileSource::parse("somesource"),
+                filename: None,
+                path: PathBuf::from("/some/path"),
+                store_policy: StorePolicy::StoreForever,
+                created: Utc::now(),
+                last_used: Utc::now(),
+                validator: None,
+                negative_status: None,
+                digest: None,
+                pinned: false,
+                uses: 0,
+            })
+            .await
+            .expect("store");
+        let file = database.get(id).await.expect("get");
+
+        // Files are kept while any clone is alive
+        let clone = database.clone();
+        drop(database);
+        assert_eq!(clone.uri(), file.database);
+        assert_eq!(clone.get(id).await.expect("get from clone"), file);
+
+        // New database doesn't share files
+        let other = MemoryStorageDatabase::new();
+        assert_ne!(other.uri(), clone.uri());
+        assert!(other.get(id).await.unwrap_err().is_not_found());
+    }
+}
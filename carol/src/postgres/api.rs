@@ -0,0 +1,397 @@
+//! Convenient API to interact with cache database.
+//!
+//! Rows are represented with the same models as in SQLite database. Entries, whose status is
+//! checked or read before being updated, are locked with `SELECT ... FOR UPDATE`, so concurrent
+//! updates from several hosts are serialized.
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    EscapeExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, TextExpressionMethods,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use tracing::trace;
+
+use super::schema::files::dsl::{self, files};
+use super::{Connection, DatabaseResult, PrimaryKey};
+use crate::diesel_common::models::{File, FileStatus, NewFile, StorePolicy};
+use crate::diesel_common::source_prefix_pattern;
+use crate::file;
+
+/// Insert new entry to database.
+pub async fn insert(connection: &mut Connection, new_entry: NewFile) -> DatabaseResult<File> {
+    connection
+        .transaction(|conn| {
+            async {
+                trace!("INSERT {:?}", new_entry);
+                let inserted = diesel::insert_into(files)
+                    .values((
+                        dsl::source.eq(&new_entry.source),
+                        dsl::cache_path.eq(&new_entry.cache_path),
+                        dsl::filename.eq(&new_entry.filename),
+                        dsl::created.eq(new_entry.created),
+                        dsl::last_used.eq(new_entry.last_used),
+                        dsl::store_policy.eq(new_entry.store_policy),
+                        dsl::store_policy_data.eq(new_entry.store_policy_data),
+                        dsl::store_policy_extra_data.eq(new_entry.store_policy_extra_data),
+                        dsl::status.eq(new_entry.status),
+                        dsl::validator.eq(&new_entry.validator),
+                        dsl::negative_status.eq(new_entry.negative_status),
+                        dsl::digest.eq(&new_entry.digest),
+                        dsl::uid.eq(&new_entry.uid),
+                        dsl::expires_at.eq(new_entry.expires_at),
+                        dsl::pinned.eq(new_entry.pinned),
+                        dsl::uses.eq(new_entry.uses),
+                    ))
+                    .get_result::<File>(conn)
+                    .await?;
+                Ok(inserted)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Get entry from database by primary key.
+pub async fn get(connection: &mut Connection, pk: PrimaryKey) -> DatabaseResult<File> {
+    trace!("SELECT * WHERE id={}", pk);
+    files.find(pk).first(connection).await.map_err(Into::into)
+}
+
+/// Delete entry from database.
+pub async fn delete(connection: &mut Connection, pk: PrimaryKey) -> DatabaseResult<()> {
+    trace!("DELETE WHERE id={}", pk);
+    diesel::delete(files.find(pk)).execute(connection).await?;
+    Ok(())
+}
+
+/// Get entry from database by source (URL or custom source).
+pub async fn get_by_source(connection: &mut Connection, source: &str) -> DatabaseResult<Vec<File>> {
+    trace!("SELECT * WHERE source={}", source);
+    files
+        .filter(dsl::source.eq(source))
+        .get_results(connection)
+        .await
+        .map_err(Into::into)
+}
+
+/// Get entries from database with sources starting with `prefix`.
+pub async fn get_by_source_prefix(
+    connection: &mut Connection,
+    prefix: &str,
+) -> DatabaseResult<Vec<File>> {
+    let pattern = source_prefix_pattern(prefix);
+    trace!("SELECT * WHERE source LIKE {}", pattern);
+    files
+        .filter(dsl::source.like(pattern).escape('\\'))
+        .get_results(connection)
+        .await
+        .map_err(Into::into)
+}
+
+/// Get all "stale" entries from the database, which expire at `now` or earlier and are not
+/// pinned.
+///
+/// Uses index of the computed `expires_at` column.
+pub async fn get_all_stale(
+    connection: &mut Connection,
+    now: DateTime<Utc>,
+) -> DatabaseResult<Vec<File>> {
+    trace!("SELECT * WHERE expires_at <= {} AND NOT pinned", now);
+    files
+        .filter(dsl::expires_at.le(now))
+        .filter(dsl::pinned.eq(false))
+        .get_results(connection)
+        .await
+        .map_err(Into::into)
+}
+
+/// Get entry from database by its globally unique identifier.
+pub async fn get_by_uid(connection: &mut Connection, uid: &str) -> DatabaseResult<Option<File>> {
+    trace!("SELECT * WHERE uid={}", uid);
+    files
+        .filter(dsl::uid.eq(uid))
+        .first(connection)
+        .await
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Update status of entry. Returns updated entry.
+pub async fn update_status(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    status: FileStatus,
+) -> DatabaseResult<File> {
+    trace!("UPDATE SET status={} WHERE id={}, ", status, pk);
+    diesel::update(files.find(pk))
+        .set(dsl::status.eq(status))
+        .get_result(connection)
+        .await
+        .map_err(Into::into)
+}
+
+/// Update status of entry only if its current status is `expected`.
+/// Returns updated entry or `None` if nothing was updated.
+///
+/// The entry is locked until the end of the transaction.
+pub async fn update_status_if(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    expected: FileStatus,
+    status: FileStatus,
+) -> DatabaseResult<Option<File>> {
+    connection
+        .transaction(|conn| {
+            async move {
+                trace!("SELECT status WHERE id={} FOR UPDATE", pk);
+                let current = files
+                    .find(pk)
+                    .select(dsl::status)
+                    .for_update()
+                    .first::<FileStatus>(conn)
+                    .await
+                    .optional()?;
+                if current != Some(expected) {
+                    return Ok(None);
+                }
+                trace!("UPDATE SET status={} WHERE id={}", status, pk);
+                diesel::update(files.find(pk))
+                    .set(dsl::status.eq(status))
+                    .get_result(conn)
+                    .await
+                    .map(Some)
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(Into::into)
+}
+
+/// Update store policy of entry and the moment when it expires. Returns updated entry.
+///
+/// The entry is locked until the end of the transaction.
+pub async fn update_store_policy(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    store_policy: file::StorePolicy,
+) -> DatabaseResult<File> {
+    let (policy, data, extra_data): (StorePolicy, _, _) = store_policy.try_into()?;
+    connection
+        .transaction(|conn| {
+            async move {
+                trace!("SELECT * WHERE id={} FOR UPDATE", pk);
+                let current = files.find(pk).for_update().first::<File>(conn).await?;
+                let mut metadata = file::FileMetadata::try_from(current)?;
+                metadata.store_policy = store_policy;
+                let expires_at = metadata.expires_at();
+                trace!(
+                    "UPDATE SET store_policy={:?}, expires_at={:?} WHERE id={}",
+                    store_policy,
+                    expires_at,
+                    pk
+                );
+                let updated = diesel::update(files.find(pk))
+                    .set((
+                        dsl::store_policy.eq(policy),
+                        dsl::store_policy_data.eq(data),
+                        dsl::store_policy_extra_data.eq(extra_data),
+                        dsl::expires_at.eq(expires_at),
+                    ))
+                    .get_result(conn)
+                    .await?;
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Update store policy of all entries with sources starting with `prefix` and the moments when
+/// they expire. Returns updated entries.
+///
+/// Entries are updated in a single transaction and stay locked until its end. The moment of
+/// expiration depends on timestamps of each entry, so it is recomputed per entry.
+pub async fn update_store_policy_by_source_prefix(
+    connection: &mut Connection,
+    prefix: &str,
+    store_policy: file::StorePolicy,
+) -> DatabaseResult<Vec<File>> {
+    let (policy, data, extra_data): (StorePolicy, _, _) = store_policy.try_into()?;
+    let pattern = source_prefix_pattern(prefix);
+    connection
+        .transaction(|conn| {
+            async move {
+                trace!(
+                    "UPDATE SET store_policy={:?} WHERE source LIKE {}",
+                    store_policy,
+                    pattern
+                );
+                let mut updated: Vec<File> =
+                    diesel::update(files.filter(dsl::source.like(&pattern).escape('\\')))
+                        .set((
+                            dsl::store_policy.eq(policy),
+                            dsl::store_policy_data.eq(data),
+                            dsl::store_policy_extra_data.eq(extra_data),
+                        ))
+                        .get_results(conn)
+                        .await?;
+                for entry in &mut updated {
+                    let expires_at = file::FileMetadata::try_from(entry.clone())?.expires_at();
+                    if entry.expires_at != expires_at {
+                        trace!(
+                            "UPDATE SET expires_at={:?} WHERE id={}",
+                            expires_at,
+                            entry.id
+                        );
+                        *entry = diesel::update(files.find(entry.id))
+                            .set(dsl::expires_at.eq(expires_at))
+                            .get_result(conn)
+                            .await?;
+                    }
+                }
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Update last used timestamp of entry to `at`, increment its number of uses and recompute the
+/// moment when it expires. Returns updated entry.
+///
+/// The entry is locked until the end of the transaction.
+pub async fn mark_used(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    at: DateTime<Utc>,
+) -> DatabaseResult<File> {
+    connection
+        .transaction(|conn| {
+            async move {
+                trace!("SELECT * WHERE id={} FOR UPDATE", pk);
+                let current = files.find(pk).for_update().first::<File>(conn).await?;
+                let mut metadata = file::FileMetadata::try_from(current)?;
+                metadata.last_used = at;
+                metadata.uses += 1;
+                let expires_at = metadata.expires_at();
+                trace!(
+                    "UPDATE SET last_used={}, uses=uses+1, expires_at={:?} WHERE id={}",
+                    at,
+                    expires_at,
+                    pk
+                );
+                let updated = diesel::update(files.find(pk))
+                    .set((
+                        dsl::last_used.eq(at),
+                        dsl::uses.eq(dsl::uses + 1),
+                        dsl::expires_at.eq(expires_at),
+                    ))
+                    .get_result(conn)
+                    .await?;
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Pin or unpin entry. Returns updated entry.
+pub async fn update_pinned(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    pinned: bool,
+) -> DatabaseResult<File> {
+    trace!("UPDATE SET pinned={} WHERE id={}", pinned, pk);
+    diesel::update(files.find(pk))
+        .set(dsl::pinned.eq(pinned))
+        .get_result(connection)
+        .await
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::StorageDatabaseError;
+    use crate::diesel_common::error::DatabaseError;
+    use crate::postgres::fixtures::{
+        database, database_with_single_entry, PostgresDatabaseFixture,
+    };
+    use rstest::rstest;
+    use tracing_test::traced_test;
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_insert_failure(
+        #[future] database_with_single_entry: (PostgresDatabaseFixture, File),
+    ) {
+        let (fixture, _) = database_with_single_entry;
+        let result = insert(
+            fixture.conn().await.as_mut(),
+            PostgresDatabaseFixture::default_new_entry(),
+        )
+        .await;
+        assert!(matches!(result, Err(ref err) if err.is_unique_violation()));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_get(#[future] database_with_single_entry: (PostgresDatabaseFixture, File)) {
+        let (fixture, inserted) = database_with_single_entry;
+        let mut conn = fixture.conn().await;
+        assert_eq!(get(conn.as_mut(), inserted.id).await.unwrap(), inserted);
+        let result = get(conn.as_mut(), inserted.id + 1).await;
+        assert!(matches!(result, Err(ref err) if err.is_not_found()));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_delete(#[future] database_with_single_entry: (PostgresDatabaseFixture, File)) {
+        let (fixture, inserted) = database_with_single_entry;
+        let mut conn = fixture.conn().await;
+        delete(conn.as_mut(), inserted.id).await.unwrap();
+        assert!(get_by_source(conn.as_mut(), &inserted.source)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_update_status_if_concurrent(#[future] database: PostgresDatabaseFixture) {
+        let inserted = database
+            .insert_entry(PostgresDatabaseFixture::default_new_entry())
+            .await;
+        // Only one of concurrent updates succeeds
+        let mut first = database.conn().await;
+        let mut second = database.conn().await;
+        let (first, second) = tokio::join!(
+            update_status_if(
+                first.as_mut(),
+                inserted.id,
+                FileStatus::Pending,
+                FileStatus::Ready
+            ),
+            update_status_if(
+                second.as_mut(),
+                inserted.id,
+                FileStatus::Pending,
+                FileStatus::Partial
+            ),
+        );
+        let updated: Vec<File> = [first, second]
+            .into_iter()
+            .filter_map(|result: Result<_, DatabaseError>| result.unwrap())
+            .collect();
+        assert_eq!(updated.len(), 1);
+    }
+}
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE "files"(
+	"id" BIGSERIAL PRIMARY KEY,
+	"source" VARCHAR NOT NULL,
+	"cache_path" VARCHAR UNIQUE NOT NULL,
+	"filename" VARCHAR,
+	"created" TIMESTAMPTZ NOT NULL,
+	"last_used" TIMESTAMPTZ NOT NULL,
+	"store_policy" INTEGER NOT NULL,
+	"store_policy_data" BIGINT,
+	"store_policy_extra_data" BIGINT,
+	"status" INTEGER NOT NULL,
+	"validator" VARCHAR,
+	"negative_status" INTEGER,
+	"digest" VARCHAR,
+	"uid" VARCHAR UNIQUE,
+	"expires_at" TIMESTAMPTZ,
+	"pinned" BOOLEAN NOT NULL DEFAULT FALSE,
+	"uses" BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX "files_source_idx" ON "files"("source");
+CREATE INDEX "files_expires_at_idx" ON "files"("expires_at");
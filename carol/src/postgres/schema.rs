@@ -0,0 +1,55 @@
+diesel::table! {
+    /// Cached files metadata.
+    files (id) {
+        /// Primary key.
+        id -> BigInt,
+
+        /// URL of the downloaded file or some other source.
+        source -> VarChar,
+
+        /// Path to downloaded file in cache.
+        cache_path -> VarChar,
+
+        /// Original file name.
+        filename -> Nullable<VarChar>,
+
+        /// Entry creation timestamp.
+        created -> Timestamptz,
+
+        /// When the file was used last time.
+        last_used -> Timestamptz,
+
+        /// Store policy.
+        store_policy -> Integer,
+
+        /// Additional store policy data, e.g. duration in microseconds.
+        store_policy_data -> Nullable<BigInt>,
+
+        /// Second duration of store policy in microseconds, if the policy has one.
+        store_policy_extra_data -> Nullable<BigInt>,
+
+        /// Current status of the cache entry.
+        status -> Integer,
+
+        /// Opaque identifier of the source content version.
+        validator -> Nullable<VarChar>,
+
+        /// Status code of a negative result.
+        negative_status -> Nullable<Integer>,
+
+        /// Expected digest of the file content.
+        digest -> Nullable<VarChar>,
+
+        /// Globally unique identifier of the file.
+        uid -> Nullable<VarChar>,
+
+        /// When the file becomes stale, computed from store policy.
+        expires_at -> Nullable<Timestamptz>,
+
+        /// Whether the file is exempt from eviction.
+        pinned -> Bool,
+
+        /// Number of times the file was used.
+        uses -> BigInt,
+    }
+}
@@ -0,0 +1,506 @@
+//! Prefetching of files into storage in the background.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::debug;
+
+use crate::database::{StorageDatabaseError, StorageDatabaseExt};
+use crate::error::StorageError;
+use crate::file::{File, FileSource, FileStatus, StorePolicy};
+use crate::storage_manager::StorageManager;
+
+/// File to be prefetched by [`PrefetchQueue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchRequest {
+    /// Source of the file.
+    pub source: FileSource,
+
+    /// Store policy of the file.
+    pub store_policy: StorePolicy,
+
+    /// Original name of the file.
+    pub filename: Option<String>,
+
+    /// Priority of the request. Requests with higher priority are started first.
+    pub priority: i32,
+}
+
+impl PrefetchRequest {
+    /// Create request with default priority.
+    pub fn new(source: FileSource, store_policy: StorePolicy) -> Self {
+        Self {
+            source,
+            store_policy,
+            filename: None,
+            priority: 0,
+        }
+    }
+
+    /// Set original name of the file.
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Set priority of the request.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Configuration of [`PrefetchQueue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchConfig {
+    /// Maximum number of files fetched at the same time. Default is 4.
+    pub concurrency: usize,
+
+    /// Maximum number of attempts to fetch a file. Default is 3.
+    pub max_attempts: usize,
+
+    /// Delay before the first retry. It is doubled after each failed attempt. Default is 1 second.
+    pub backoff: Duration,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            max_attempts: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Progress of [`PrefetchQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefetchProgress {
+    /// Number of queued requests.
+    pub total: usize,
+
+    /// Number of files fetched into storage.
+    pub fetched: usize,
+
+    /// Number of requests skipped, because the file is already in storage.
+    pub skipped: usize,
+
+    /// Number of requests failed after all attempts.
+    pub failed: usize,
+
+    /// Number of retried attempts.
+    pub retries: usize,
+}
+
+impl PrefetchProgress {
+    /// Number of finished requests.
+    pub fn completed(&self) -> usize {
+        self.fetched + self.skipped + self.failed
+    }
+
+    /// Returns `true` if all queued requests are finished.
+    pub fn is_finished(&self) -> bool {
+        self.completed() == self.total
+    }
+}
+
+/// Successful outcome of a prefetch request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefetchOutcome {
+    /// File was fetched into storage.
+    Fetched(File),
+
+    /// File was already [`FileStatus::Ready`], nothing was fetched.
+    AlreadyReady(File),
+
+    /// File was already being written ([`FileStatus::Pending`]), nothing was fetched.
+    AlreadyPending(File),
+}
+
+/// Result of a prefetch request.
+#[derive(Debug)]
+pub struct PrefetchResult<E: StorageDatabaseError> {
+    /// Source of the requested file.
+    pub source: FileSource,
+
+    /// Number of attempts made to fetch the file.
+    pub attempts: usize,
+
+    /// Outcome of the request or the error of the last attempt.
+    pub outcome: Result<PrefetchOutcome, StorageError<E>>,
+}
+
+/// Queue of files to be fetched into storage in the background.
+///
+/// Created with [`StorageManager::prefetch_queue`]. Content of each file is obtained by `open`
+/// callback, which is called with the source of the file and returns a stream of its content.
+///
+/// Requests are started in order of their priority with at most
+/// [`PrefetchConfig::concurrency`] of them running at the same time. Files, which are already
+/// [`FileStatus::Ready`] or [`FileStatus::Pending`] in storage, are not fetched again. If opening
+/// or reading the stream fails, fetching is retried with exponential backoff. If the file is stored
+/// concurrently by someone else, the other write is awaited and its file is returned. Storage
+/// errors, including transient database errors, are not retried and are reported in the result of
+/// the request.
+///
+/// # Example
+///
+/// ```rust
+/// # tokio_test::block_on(async {
+/// use bytes::Bytes;
+/// use carol::{FileSource, PrefetchConfig, PrefetchRequest, StorageManager, StorePolicy};
+/// use futures_util::stream;
+///
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let database_url = dir.path().join("carol.sqlite");
+/// # let database_url = database_url.to_str().unwrap();
+/// # let cache_dir = dir.path();
+/// let manager = StorageManager::init(database_url, cache_dir, None).await.unwrap();
+///
+/// let mut queue = manager.prefetch_queue(PrefetchConfig::default(), |source: FileSource| async move {
+///     // Fetch content of the file, e.g. with an HTTP client
+///     let content = Bytes::from(source.to_string());
+///     Ok::<_, std::io::Error>(stream::iter([Ok(content)]))
+/// });
+/// queue.push(PrefetchRequest::new(FileSource::parse("https://example.com/a"), StorePolicy::StoreForever));
+/// queue.push(
+///     PrefetchRequest::new(FileSource::parse("https://example.com/b"), StorePolicy::StoreForever)
+///         .with_priority(10),
+/// );
+///
+/// let progress = queue.subscribe();
+/// let results = queue.run().await;
+/// assert_eq!(results.len(), 2);
+/// assert_eq!(progress.borrow().fetched, 2);
+/// # })
+/// ```
+pub struct PrefetchQueue<D: StorageDatabaseExt, F> {
+    manager: StorageManager<D>,
+    config: PrefetchConfig,
+    open: F,
+    requests: Vec<PrefetchRequest>,
+    /// Indexes of queued requests by their sources.
+    queued: HashMap<FileSource, usize>,
+    progress: watch::Sender<PrefetchProgress>,
+}
+
+impl<D: StorageDatabaseExt, F> PrefetchQueue<D, F> {
+    pub(crate) fn new(manager: StorageManager<D>, config: PrefetchConfig, open: F) -> Self {
+        Self {
+            manager,
+            config,
+            open,
+            requests: Vec::new(),
+            queued: HashMap::new(),
+            progress: watch::Sender::new(PrefetchProgress::default()),
+        }
+    }
+
+    /// Add request to the queue.
+    ///
+    /// If the queue already has a request with the same source, only its priority is raised to
+    /// the priority of the new request.
+    pub fn push(&mut self, request: PrefetchRequest) {
+        match self.queued.get(&request.source) {
+            Some(&index) => {
+                let queued = &mut self.requests[index];
+                queued.priority = queued.priority.max(request.priority);
+            }
+            None => {
+                self.queued
+                    .insert(request.source.clone(), self.requests.len());
+                self.requests.push(request);
+                self.progress.send_modify(|progress| progress.total += 1);
+            }
+        }
+    }
+
+    /// Subscribe to progress updates of the queue.
+    pub fn subscribe(&self) -> watch::Receiver<PrefetchProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Fetch all queued files.
+    ///
+    /// Returns results of all requests in order of their completion. The returned future can be
+    /// spawned to run the queue in the background.
+    pub async fn run<Fut, S, E>(mut self) -> Vec<PrefetchResult<D::Error>>
+    where
+        F: Fn(FileSource) -> Fut,
+        Fut: Future<Output = Result<S, E>>,
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: StdError + 'static + Send + Sync,
+    {
+        let mut requests = std::mem::take(&mut self.requests);
+        self.queued.clear();
+        // Sort is stable, so requests with equal priority keep their order
+        requests.sort_by_key(|request| Reverse(request.priority));
+        stream::iter(requests)
+            .map(|request| self.prefetch(request))
+            .buffer_unordered(self.config.concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    async fn prefetch<Fut, S, E>(&self, request: PrefetchRequest) -> PrefetchResult<D::Error>
+    where
+        F: Fn(FileSource) -> Fut,
+        Fut: Future<Output = Result<S, E>>,
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: StdError + 'static + Send + Sync,
+    {
+        let mut attempts = 0;
+        let outcome = match self.manager.find_by_source(&request.source).await {
+            Ok(Some(file)) if file.status == FileStatus::Ready => {
+                Ok(PrefetchOutcome::AlreadyReady(file))
+            }
+            Ok(Some(file)) if file.status == FileStatus::Pending => {
+                Ok(PrefetchOutcome::AlreadyPending(file))
+            }
+            Ok(_) => {
+                attempts += 1;
+                let mut result = self.fetch(&request).await;
+                let mut delay = self.config.backoff;
+                // Only failures of `open` callback and its stream are retried
+                while matches!(result, Err(StorageError::CustomError(_)))
+                    && attempts < self.config.max_attempts
+                {
+                    debug!(
+                        "retrying prefetch of {} in {:?}",
+                        request.source.to_string(),
+                        delay,
+                    );
+                    self.progress.send_modify(|progress| progress.retries += 1);
+                    time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    attempts += 1;
+                    result = self.fetch(&request).await;
+                }
+                result.map(PrefetchOutcome::Fetched)
+            }
+            Err(err) => Err(err),
+        };
+        self.progress.send_modify(|progress| match &outcome {
+            Ok(PrefetchOutcome::Fetched(_)) => progress.fetched += 1,
+            Ok(_) => progress.skipped += 1,
+            Err(_) => progress.failed += 1,
+        });
+        PrefetchResult {
+            source: request.source,
+            attempts,
+            outcome,
+        }
+    }
+
+    async fn fetch<Fut, S, E>(
+        &self,
+        request: &PrefetchRequest,
+    ) -> Result<File, StorageError<D::Error>>
+    where
+        F: Fn(FileSource) -> Fut,
+        Fut: Future<Output = Result<S, E>>,
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: StdError + 'static + Send + Sync,
+    {
+        let stream = (self.open)(request.source.clone())
+            .await
+            .map_err(StorageError::custom)?;
+        self.manager
+            .add_file_from_stream(
+                request.source.clone(),
+                request.store_policy,
+                request.filename.clone(),
+                stream,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures_util::stream;
+    use rstest::rstest;
+
+    use super::{PrefetchConfig, PrefetchOutcome, PrefetchProgress, PrefetchRequest};
+    use crate::{FileSource, FileStatus, StorageManager, StorePolicy};
+
+    fn request(source: &str, priority: i32) -> PrefetchRequest {
+        PrefetchRequest::new(
+            FileSource::Custom(source.to_string()),
+            StorePolicy::StoreForever,
+        )
+        .with_priority(priority)
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_order_and_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let database_url = tmp.path().join("carol.sqlite");
+        let manager = StorageManager::init(database_url.to_str().unwrap(), tmp.path(), None)
+            .await
+            .unwrap();
+        manager
+            .add_file_from_stream(
+                FileSource::Custom("stored".to_string()),
+                StorePolicy::StoreForever,
+                None,
+                stream::iter([Ok::<_, io::Error>(Bytes::from("stored"))]),
+            )
+            .await
+            .unwrap();
+
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let config = PrefetchConfig {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let opened_clone = opened.clone();
+        let mut queue = manager.prefetch_queue(config, move |source: FileSource| {
+            opened_clone.lock().unwrap().push(source.to_string());
+            async move { Ok::<_, io::Error>(stream::iter([Ok(Bytes::from(source.to_string()))])) }
+        });
+        queue.push(request("low", -1));
+        queue.push(request("stored", 5));
+        queue.push(request("default", 0));
+        queue.push(request("high", 1));
+        // Duplicate raises priority of the queued request
+        queue.push(request("low", 2));
+        let progress = queue.subscribe();
+        assert_eq!(progress.borrow().total, 4);
+
+        // Queue can be run in the background
+        let results = tokio::spawn(queue.run()).await.unwrap();
+        assert_eq!(*opened.lock().unwrap(), ["low", "high", "default"]);
+        assert_eq!(results.len(), 4);
+        for result in &results {
+            match result.outcome.as_ref().unwrap() {
+                PrefetchOutcome::AlreadyReady(file) => {
+                    assert_eq!(result.source.to_string(), "stored");
+                    assert_eq!(result.attempts, 0);
+                    assert_eq!(file.status, FileStatus::Ready);
+                }
+                PrefetchOutcome::Fetched(file) => {
+                    assert_eq!(result.attempts, 1);
+                    let content = std::fs::read_to_string(&file.metadata.path).unwrap();
+                    assert_eq!(content, result.source.to_string());
+                }
+                outcome => panic!("unexpected outcome: {outcome:?}"),
+            }
+        }
+        assert_eq!(
+            *progress.borrow(),
+            PrefetchProgress {
+                total: 4,
+                fetched: 3,
+                skipped: 1,
+                failed: 0,
+                retries: 0,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case::recovered(2, 3, true)]
+    #[case::exhausted(3, 3, false)]
+    #[trace]
+    #[tokio::test]
+    async fn test_prefetch_retries(
+        #[case] failures: usize,
+        #[case] max_attempts: usize,
+        #[case] expect_fetched: bool,
+    ) {
+        let tmp = tempfile::tempdir().unwrap();
+        let database_url = tmp.path().join("carol.sqlite");
+        let manager = StorageManager::init(database_url.to_str().unwrap(), tmp.path(), None)
+            .await
+            .unwrap();
+
+        let calls = Arc::new(Mutex::new(0));
+        let config = PrefetchConfig {
+            concurrency: 1,
+            max_attempts,
+            backoff: Duration::from_millis(1),
+        };
+        let mut queue = manager.prefetch_queue(config, |_| {
+            let mut calls = calls.lock().unwrap();
+            *calls += 1;
+            let result = if *calls > failures {
+                Ok(stream::iter([Ok(Bytes::from("content"))]))
+            } else {
+                Err(io::Error::other("unavailable"))
+            };
+            async move { result }
+        });
+        queue.push(request("flaky", 0));
+        let progress = queue.subscribe();
+
+        let results = queue.run().await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].outcome.is_ok(), expect_fetched);
+        assert_eq!(results[0].attempts, (failures + 1).min(max_attempts));
+
+        let progress = *progress.borrow();
+        assert!(progress.is_finished());
+        assert_eq!(progress.fetched, expect_fetched as usize);
+        assert_eq!(progress.failed, !expect_fetched as usize);
+        assert_eq!(progress.retries, results[0].attempts - 1);
+        let file = manager
+            .find_by_source(&FileSource::Custom("flaky".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(file.is_some(), expect_fetched);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_stored_concurrently() {
+        let tmp = tempfile::tempdir().unwrap();
+        let database_url = tmp.path().join("carol.sqlite");
+        let manager = StorageManager::init(database_url.to_str().unwrap(), tmp.path(), None)
+            .await
+            .unwrap();
+
+        let config = PrefetchConfig::default();
+        let other = manager.clone();
+        let mut queue = manager.prefetch_queue(config, move |source: FileSource| {
+            let other = other.clone();
+            async move {
+                // Someone else stores the file while it's being opened
+                other
+                    .add_file_from_stream(
+                        source,
+                        StorePolicy::StoreForever,
+                        None,
+                        stream::iter([Ok::<_, io::Error>(Bytes::from("other"))]),
+                    )
+                    .await
+                    .unwrap();
+                Ok::<_, io::Error>(stream::iter([Ok(Bytes::from("content"))]))
+            }
+        });
+        queue.push(request("raced", 0));
+        let progress = queue.subscribe();
+
+        let results = queue.run().await;
+        assert_eq!(results[0].attempts, 1);
+        // File stored by someone else is returned instead of an error
+        let PrefetchOutcome::Fetched(file) = results[0].outcome.as_ref().unwrap() else {
+            panic!("unexpected outcome: {:?}", results[0].outcome);
+        };
+        let content = std::fs::read_to_string(&file.metadata.path).unwrap();
+        assert_eq!(content, "other");
+        assert_eq!(progress.borrow().fetched, 1);
+        assert_eq!(progress.borrow().retries, 0);
+    }
+}
@@ -0,0 +1,524 @@
+//! Implementation of storage database with embedded [redb](https://www.redb.org) key-value store.
+//!
+//! The database is a single file written by pure Rust code, so no C libraries are needed. It
+//! can be opened by only one process at a time, which suits single-process deployments.
+//!
+//! Files are kept in `files` table by their IDs, serialized to JSON. Tables `sources` and `paths`
+//! index the files and keep their sources and paths unique. Every operation runs in a single
+//! ACID transaction. Read transactions are short and run on the calling task. Write transactions
+//! wait for the data to be synced to disk on commit, so they run on blocking threads.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::redb::{Database, ReadableTable, TableDefinition};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::{self, JoinError};
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::database::{StorageDatabase, StorageDatabaseError, StorageDatabaseExt};
+use crate::error::NonUtf8PathError;
+use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
+
+#[doc(no_inline)]
+pub use ::redb::Error as RedbError;
+
+/// Files by their IDs.
+const FILES: TableDefinition<i64, &[u8]> = TableDefinition::new("files");
+
+/// IDs of files by their sources.
+const SOURCES: TableDefinition<&str, i64> = TableDefinition::new("sources");
+
+/// IDs of files by their paths.
+const PATHS: TableDefinition<&str, i64> = TableDefinition::new("paths");
+
+/// IDs of files by their globally unique identifiers.
+const UIDS: TableDefinition<u128, i64> = TableDefinition::new("uids");
+
+/// Error of redb database operations.
+#[derive(thiserror::Error, Debug)]
+pub enum RedbDatabaseError {
+    #[error(transparent)]
+    RedbError(Box<RedbError>),
+
+    /// Failed to serialize or deserialize stored file.
+    #[error("failed to serialize file")]
+    SerializationError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    NonUtf8PathError(#[from] NonUtf8PathError),
+
+    /// File with the same source or path already exists.
+    #[error("file with the same source or path already exists")]
+    UniqueViolation,
+
+    /// File with given ID does not exist.
+    #[error("file not found")]
+    NotFound,
+
+    /// Blocking task running write transaction failed.
+    #[error("database task failed")]
+    TaskError(#[from] JoinError),
+}
+
+macro_rules! impl_from_redb_error {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for RedbDatabaseError {
+                fn from(error: $error) -> Self {
+                    Self::RedbError(Box::new(error.into()))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_redb_error!(
+    RedbError,
+    ::redb::DatabaseError,
+    ::redb::TransactionError,
+    ::redb::TableError,
+    ::redb::StorageError,
+    ::redb::CommitError
+);
+
+impl StorageDatabaseError for RedbDatabaseError {
+    fn is_unique_violation(&self) -> bool {
+        matches!(*self, Self::UniqueViolation)
+    }
+
+    fn is_not_found(&self) -> bool {
+        matches!(*self, Self::NotFound)
+    }
+}
+
+/// Stored file, except of its ID, which is the key.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    status: FileStatus,
+    metadata: FileMetadata,
+    uid: Uuid,
+}
+
+/// Storage database backed by redb.
+///
+/// Clones of the database share the same opened database file.
+#[derive(Clone)]
+pub struct RedbStorageDatabase {
+    path: PathBuf,
+    database: Arc<Database>,
+}
+
+impl RedbStorageDatabase {
+    /// Open database at `path`. If database does not exist, it will be created.
+    ///
+    /// Tables are created by [`StorageDatabase::setup`], which must be called before the database
+    /// is used, e.g. by [`StorageManagerBuilder::build`](crate::StorageManagerBuilder::build).
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RedbDatabaseError> {
+        let path = path.as_ref();
+        path.to_str().ok_or(NonUtf8PathError)?;
+        let database = Database::create(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            database: Arc::new(database),
+        })
+    }
+
+    fn record_to_file(&self, id: i64, record: &[u8]) -> Result<File, RedbDatabaseError> {
+        let Record {
+            status,
+            metadata,
+            uid,
+        } = serde_json::from_slice(record)?;
+        Ok(File {
+            database: self.uri(),
+            id: id.into(),
+            uid: Some(uid),
+            status,
+            metadata,
+        })
+    }
+
+    /// Run `operation`, which commits a write transaction, on a blocking thread.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&Self) -> Result<T, RedbDatabaseError> + Send + 'static,
+    ) -> Result<T, RedbDatabaseError> {
+        let database = self.clone();
+        task::spawn_blocking(move || operation(&database)).await?
+    }
+
+    /// Update record of file with `id` with `update`, unless it returns `false`.
+    async fn update_with(
+        &self,
+        id: FileId,
+        update: impl FnOnce(&mut Record) -> bool + Send + 'static,
+    ) -> Result<Option<File>, RedbDatabaseError> {
+        let id: i64 = id.into();
+        self.blocking(move |database| {
+            let transaction = database.database.begin_write()?;
+            let file = {
+                let mut files = transaction.open_table(FILES)?;
+                let mut record: Record = match files.get(id)? {
+                    Some(record) => serde_json::from_slice(record.value())?,
+                    None => return Err(RedbDatabaseError::NotFound),
+                };
+                if !update(&mut record) {
+                    return Ok(None);
+                }
+                let value = serde_json::to_vec(&record)?;
+                files.insert(id, value.as_slice())?;
+                database.record_to_file(id, &value)?
+            };
+            transaction.commit()?;
+            Ok(Some(file))
+        })
+        .await
+    }
+
+    /// Set status of file with `id`, if its current status satisfies `check`.
+    async fn update_status_with(
+        &self,
+        id: FileId,
+        new_status: FileStatus,
+        check: impl FnOnce(FileStatus) -> bool + Send + 'static,
+    ) -> Result<Option<File>, RedbDatabaseError> {
+        self.update_with(id, move |record| {
+            if !check(record.status) {
+                return false;
+            }
+            trace!("UPDATE SET status={} WHERE id={}", new_status, id);
+            record.status = new_status;
+            true
+        })
+        .await
+    }
+}
+
+impl fmt::Debug for RedbStorageDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedbStorageDatabase")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl StorageDatabase for RedbStorageDatabase {
+    type Uri = String;
+
+    type Error = RedbDatabaseError;
+
+    fn uri(&self) -> String {
+        // Checked to be UTF-8 on opening
+        self.path.to_string_lossy().into_owned()
+    }
+
+    /// Create tables, so read transactions can open them.
+    async fn setup(&self) -> Result<(), Self::Error> {
+        self.blocking(|database| {
+            let transaction = database.database.begin_write()?;
+            transaction.open_table(FILES)?;
+            transaction.open_table(SOURCES)?;
+            transaction.open_table(PATHS)?;
+            transaction.open_table(UIDS)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store(&self, metadata: FileMetadata) -> Result<FileId, Self::Error> {
+        let path = metadata.path.to_str().ok_or(NonUtf8PathError)?.to_string();
+        let source = metadata.source.as_str().to_string();
+        self.blocking(move |database| {
+            let transaction = database.database.begin_write()?;
+            let id = {
+                let mut files = transaction.open_table(FILES)?;
+                let mut sources = transaction.open_table(SOURCES)?;
+                let mut paths = transaction.open_table(PATHS)?;
+                let mut uids = transaction.open_table(UIDS)?;
+                if sources.get(source.as_str())?.is_some() || paths.get(path.as_str())?.is_some() {
+                    return Err(RedbDatabaseError::UniqueViolation);
+                }
+                let id = match files.last()? {
+                    Some((last, _)) => last.value() + 1,
+                    None => 1,
+                };
+                trace!("INSERT id={} source={}", id, source);
+                let uid = Uuid::now_v7();
+                let record = serde_json::to_vec(&Record {
+                    status: FileStatus::default(),
+                    metadata,
+                    uid,
+                })?;
+                files.insert(id, record.as_slice())?;
+                sources.insert(source.as_str(), id)?;
+                paths.insert(path.as_str(), id)?;
+                uids.insert(uid.as_u128(), id)?;
+                id
+            };
+            transaction.commit()?;
+            Ok(id.into())
+        })
+        .await
+    }
+
+    async fn get(&self, id: FileId) -> Result<File, Self::Error> {
+        let id: i64 = id.into();
+        trace!("SELECT * WHERE id={}", id);
+        let transaction = self.database.begin_read()?;
+        let files = transaction.open_table(FILES)?;
+        let record = files.get(id)?.ok_or(RedbDatabaseError::NotFound)?;
+        self.record_to_file(id, record.value())
+    }
+
+    async fn remove(&self, id: FileId) -> Result<(), Self::Error> {
+        let id: i64 = id.into();
+        trace!("DELETE WHERE id={}", id);
+        self.blocking(move |database| {
+            let transaction = database.database.begin_write()?;
+            {
+                let mut files = transaction.open_table(FILES)?;
+                let Some(record) = files.remove(id)? else {
+                    return Ok(());
+                };
+                let Record { metadata, uid, .. } = serde_json::from_slice(record.value())?;
+                let mut sources = transaction.open_table(SOURCES)?;
+                sources.remove(metadata.source.as_str())?;
+                let mut paths = transaction.open_table(PATHS)?;
+                paths.remove(metadata.path.to_str().ok_or(NonUtf8PathError)?)?;
+                let mut uids = transaction.open_table(UIDS)?;
+                uids.remove(uid.as_u128())?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl StorageDatabaseExt for RedbStorageDatabase {
+    async fn select_by_source(&self, source: &FileSource) -> Result<Vec<File>, Self::Error> {
+        trace!("SELECT * WHERE source={}", source);
+        let transaction = self.database.begin_read()?;
+        let sources = transaction.open_table(SOURCES)?;
+        let Some(id) = sources.get(source.as_str())?.map(|id| id.value()) else {
+            return Ok(Vec::new());
+        };
+        let files = transaction.open_table(FILES)?;
+        let record = files.get(id)?.ok_or(RedbDatabaseError::NotFound)?;
+        Ok(vec![self.record_to_file(id, record.value())?])
+    }
+
+    async fn select_by_uid(&self, uid: Uuid) -> Result<Option<File>, Self::Error> {
+        trace!("SELECT * WHERE uid={}", uid);
+        let transaction = self.database.begin_read()?;
+        let uids = transaction.open_table(UIDS)?;
+        let Some(id) = uids.get(uid.as_u128())?.map(|id| id.value()) else {
+            return Ok(None);
+        };
+        let files = transaction.open_table(FILES)?;
+        let record = files.get(id)?.ok_or(RedbDatabaseError::NotFound)?;
+        self.record_to_file(id, record.value()).map(Some)
+    }
+
+    async fn select_by_source_prefix(&self, prefix: &str) -> Result<Vec<File>, Self::Error> {
+        trace!("SELECT * WHERE source LIKE {}%", prefix);
+        let transaction = self.database.begin_read()?;
+        let sources = transaction.open_table(SOURCES)?;
+        let files = transaction.open_table(FILES)?;
+        let mut selected = Vec::new();
+        for entry in sources.range(prefix..)? {
+            let (source, id) = entry?;
+            if !source.value().starts_with(prefix) {
+                break;
+            }
+            let id = id.value();
+            let record = files.get(id)?.ok_or(RedbDatabaseError::NotFound)?;
+            selected.push(self.record_to_file(id, record.value())?);
+        }
+        Ok(selected)
+    }
+
+    async fn select_stale(&self, now: DateTime<Utc>) -> Result<Vec<File>, Self::Error> {
+        trace!("SELECT * WHERE expires_at <= {} AND NOT pinned", now);
+        let transaction = self.database.begin_read()?;
+        let files = transaction.open_table(FILES)?;
+        let mut selected = Vec::new();
+        for entry in files.iter()? {
+            let (id, record) = entry?;
+            let file = self.record_to_file(id.value(), record.value())?;
+            if file.metadata.is_stale(now) {
+                selected.push(file);
+            }
+        }
+        Ok(selected)
+    }
+
+    async fn update_status(&self, id: FileId, new_status: FileStatus) -> Result<File, Self::Error> {
+        self.update_status_with(id, new_status, |_| true)
+            .await
+            .map(|file| file.expect("status is updated unconditionally"))
+    }
+
+    async fn update_status_if(
+        &self,
+        id: FileId,
+        expected: FileStatus,
+        new_status: FileStatus,
+    ) -> Result<Option<File>, Self::Error> {
+        match self
+            .update_status_with(id, new_status, move |status| status == expected)
+            .await
+        {
+            Err(RedbDatabaseError::NotFound) => Ok(None),
+            result => result,
+        }
+    }
+
+    async fn update_store_policy(
+        &self,
+        id: FileId,
+        store_policy: StorePolicy,
+    ) -> Result<File, Self::Error> {
+        self.update_with(id, move |record| {
+            trace!("UPDATE SET store_policy={:?} WHERE id={}", store_policy, id);
+            record.metadata.store_policy = store_policy;
+            true
+        })
+        .await
+        .map(|file| file.expect("store policy is updated unconditionally"))
+    }
+
+    async fn update_store_policy_by_source_prefix(
+        &self,
+        prefix: &str,
+        store_policy: StorePolicy,
+    ) -> Result<Vec<File>, Self::Error> {
+        trace!(
+            "UPDATE SET store_policy={:?} WHERE source LIKE {}%",
+            store_policy,
+            prefix
+        );
+        let prefix = prefix.to_string();
+        self.blocking(move |database| {
+            let prefix = prefix.as_str();
+            let transaction = database.database.begin_write()?;
+            let mut updated = Vec::new();
+            {
+                let sources = transaction.open_table(SOURCES)?;
+                let mut files = transaction.open_table(FILES)?;
+                for entry in sources.range(prefix..)? {
+                    let (source, id) = entry?;
+                    if !source.value().starts_with(prefix) {
+                        break;
+                    }
+                    let id = id.value();
+                    let mut record: Record = match files.get(id)? {
+                        Some(record) => serde_json::from_slice(record.value())?,
+                        None => return Err(RedbDatabaseError::NotFound),
+                    };
+                    record.metadata.store_policy = store_policy;
+                    let value = serde_json::to_vec(&record)?;
+                    files.insert(id, value.as_slice())?;
+                    updated.push(database.record_to_file(id, &value)?);
+                }
+            }
+            transaction.commit()?;
+            Ok(updated)
+        })
+        .await
+    }
+
+    async fn mark_used(&self, id: FileId, at: DateTime<Utc>) -> Result<File, Self::Error> {
+        self.update_with(id, move |record| {
+            trace!("UPDATE SET last_used={}, uses=uses+1 WHERE id={}", at, id);
+            record.metadata.last_used = at;
+            record.metadata.uses += 1;
+            true
+        })
+        .await
+        .map(|file| file.expect("file is marked as used unconditionally"))
+    }
+
+    async fn update_pinned(&self, id: FileId, pinned: bool) -> Result<File, Self::Error> {
+        self.update_with(id, move |record| {
+            trace!("UPDATE SET pinned={} WHERE id={}", pinned, id);
+            record.metadata.pinned = pinned;
+            true
+        })
+        .await
+        .map(|file| file.expect("file is pinned unconditionally"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RedbStorageDatabase;
+    use crate::database::{StorageDatabase, StorageDatabaseError};
+    use crate::file::{FileMetadata, FileSource, StorePolicy};
+    use chrono::Utc;
+    use rstest::{fixture, rstest};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    #[derive(Debug)]
+    struct RedbDatabaseFixture {
+        database: RedbStorageDatabase,
+        _dir: TempDir,
+    }
+
+    #[fixture]
+    async fn database() -> RedbDatabaseFixture {
+        let dir = tempfile::tempdir().unwrap();
+        let database = RedbStorageDatabase::open(dir.path().join("carol.redb")).expect("open");
+        database.setup().await.expect("setup");
+        RedbDatabaseFixture {
+            database,
+            _dir: dir,
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_conformance(#[future] database: RedbDatabaseFixture) {
+        crate::conformance::run(&database.database).await;
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("carol.redb");
+        let metadata = FileMetadata {
+            source: FileSource::parse("somesource"),
+            filename: Some("file.txt".to_string()),
+            path: PathBuf::from("/some/path"),
+            store_policy: StorePolicy::StoreForever,
+            created: Utc::now(),
+            last_used: Utc::now(),
+            validator: None,
+            negative_status: None,
+            digest: None,
+            pinned: false,
+            uses: 0,
+        };
+        let database = RedbStorageDatabase::open(&path).expect("open");
+        database.setup().await.expect("setup");
+        let id = database.store(metadata.clone()).await.expect("store");
+        let file = database.get(id).await.expect("get");
+        drop(database);
+
+        let database = RedbStorageDatabase::open(&path).expect("reopen");
+        database.setup().await.expect("setup again");
+        assert_eq!(database.get(id).await.expect("get"), file);
+        let err = database.store(metadata).await.unwrap_err();
+        assert!(err.is_unique_violation());
+    }
+}
@@ -2,14 +2,28 @@
 //!
 //! Basically just fancy wrappers around transactions on [`Connection`].
 
-use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use chrono::{DateTime, Utc};
+use diesel::{
+    define_sql_function, EscapeExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl,
+    TextExpressionMethods,
+};
 use diesel_async::scoped_futures::ScopedFutureExt;
 use diesel_async::{AsyncConnection, RunQueryDsl};
 use tracing::trace;
 
-use super::models::{File, FileStatus, NewFile};
 use super::schema::files::dsl::{self, files};
 use super::{Connection, DatabaseResult, PrimaryKey};
+use crate::diesel_common::models::{File, FileStatus, NewFile, StorePolicy};
+use crate::diesel_common::source_prefix_pattern;
+use crate::file;
+
+define_sql_function! {
+    /// Position of the first occurrence of `needle` in `haystack`, starting from 1, or 0.
+    fn instr(
+        haystack: diesel::sql_types::Text,
+        needle: diesel::sql_types::Text,
+    ) -> diesel::sql_types::Integer;
+}
 
 /// Insert new entry to database.
 pub async fn insert(connection: &mut Connection, new_entry: NewFile) -> DatabaseResult<File> {
@@ -18,7 +32,24 @@ pub async fn insert(connection: &mut Connection, new_entry: NewFile) -> Database
             async {
                 trace!("INSERT {:?}", new_entry);
                 let inserted = diesel::insert_into(files)
-                    .values(&new_entry)
+                    .values((
+                        dsl::source.eq(&new_entry.source),
+                        dsl::cache_path.eq(&new_entry.cache_path),
+                        dsl::filename.eq(&new_entry.filename),
+                        dsl::created.eq(new_entry.created),
+                        dsl::last_used.eq(new_entry.last_used),
+                        dsl::store_policy.eq(new_entry.store_policy),
+                        dsl::store_policy_data.eq(new_entry.store_policy_data),
+                        dsl::store_policy_extra_data.eq(new_entry.store_policy_extra_data),
+                        dsl::status.eq(new_entry.status),
+                        dsl::validator.eq(&new_entry.validator),
+                        dsl::negative_status.eq(new_entry.negative_status),
+                        dsl::digest.eq(&new_entry.digest),
+                        dsl::uid.eq(&new_entry.uid),
+                        dsl::expires_at.eq(new_entry.expires_at),
+                        dsl::pinned.eq(new_entry.pinned),
+                        dsl::uses.eq(new_entry.uses),
+                    ))
                     .get_result::<File>(conn)
                     .await?;
                 Ok(inserted)
@@ -34,7 +65,7 @@ pub async fn get(connection: &mut Connection, pk: PrimaryKey) -> DatabaseResult<
         .transaction(|conn| {
             async {
                 trace!("SELECT * WHERE id={}", pk);
-                files.find(pk).select(File::as_select()).first(conn).await
+                files.find(pk).first::<File>(conn).await
             }
             .scope_boxed()
         })
@@ -62,7 +93,7 @@ pub async fn get_all(connection: &mut Connection) -> DatabaseResult<Vec<File>> {
         .transaction(|conn| {
             async {
                 trace!("SELECT *");
-                files.select(File::as_select()).get_results(conn).await
+                files.get_results::<File>(conn).await
             }
             .scope_boxed()
         })
@@ -76,14 +107,47 @@ pub async fn get_by_source(connection: &mut Connection, source: &str) -> Databas
         .transaction(|conn| {
             async {
                 trace!("SELECT * WHERE source={}", source);
+                files.filter(dsl::source.eq(source)).get_results(conn).await
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(Into::into)
+}
+
+/// Get entries from database with sources starting with `prefix`.
+pub async fn get_by_source_prefix(
+    connection: &mut Connection,
+    prefix: &str,
+) -> DatabaseResult<Vec<File>> {
+    let pattern = source_prefix_pattern(prefix);
+    let mut selected = connection
+        .transaction(|conn| {
+            async {
+                trace!("SELECT * WHERE source LIKE {}", pattern);
                 files
-                    .filter(dsl::source.eq(source))
-                    .select(File::as_select())
-                    .get_results(conn)
+                    .filter(dsl::source.like(&pattern).escape('\\'))
+                    .get_results::<File>(conn)
                     .await
             }
             .scope_boxed()
         })
+        .await?;
+    // LIKE of SQLite ignores case of ASCII letters
+    selected.retain(|file| file.source.starts_with(prefix));
+    Ok(selected)
+}
+
+/// Get entry from database by its globally unique identifier.
+pub async fn get_by_uid(connection: &mut Connection, uid: &str) -> DatabaseResult<Option<File>> {
+    connection
+        .transaction(|conn| {
+            async {
+                trace!("SELECT * WHERE uid={}", uid);
+                files.filter(dsl::uid.eq(uid)).first(conn).await.optional()
+            }
+            .scope_boxed()
+        })
         .await
         .map_err(Into::into)
 }
@@ -99,7 +163,6 @@ pub async fn get_by_cache_path(
                 trace!("SELECT * WHERE cache_path={}", cache_path);
                 files
                     .filter(dsl::cache_path.eq(cache_path))
-                    .select(File::as_select())
                     .first(conn)
                     .await
                     .optional()
@@ -119,11 +182,7 @@ pub async fn get_by_status(
         .transaction(|conn| {
             async {
                 trace!("SELECT * WHERE status={}", status);
-                files
-                    .filter(dsl::status.eq(status))
-                    .select(File::as_select())
-                    .get_results(conn)
-                    .await
+                files.filter(dsl::status.eq(status)).get_results(conn).await
             }
             .scope_boxed()
         })
@@ -152,41 +211,206 @@ pub async fn update_status(
         .map_err(Into::into)
 }
 
-/// Get all "stale" cache entries from the database.
-pub async fn get_all_stale(_connection: &mut Connection) -> DatabaseResult<Vec<File>> {
-    // connection
-    //     .transaction(|conn| {
-    //         async {
-    //             // let min_months_predicate = a::min_unit
-    //             //     .eq(Some("Months"))
-    //             //     .and(a::min_duration.le(Some(param1)));
-    //             // let min_years_predicate = a::min_unit
-    //             //     .eq(Some("Years"))
-    //             //     .and(a::min_duration.le(Some(param1)));
-
-    //             // query = query
-    //             //     .filter(min_months_predicate.or(min_years_predicate))
-    //             //     .filter(a::max_duration.ge(Some(param2)));
-    //             // let x = conn.batch_execute("").await;
-
-    //             trace!("SELECT * WHERE (store_policy=ExpiresAfter AND store_policy_data + created < NOW())");
-    //             let expired_after = dsl::store_policy
-    //                 .eq(StorePolicy::ExpiresAfter)
-    //                 .and((dsl::store_policy_data).gt(123));
-    //             let expired_after_not_used = dsl::store_policy
-    //                 .eq(StorePolicy::ExpiresAfterNotUsedFor)
-    //                 .and(dsl::store_policy_data.gt(123));
-    //             files
-    //                 .filter(expired_after.or(expired_after_not_used))
-    //                 .select(File::as_select())
-    //                 .get_results(conn)
-    //                 .await
-    //         }
-    //         .scope_boxed()
-    //     })
-    //     .await
-    //     .map_err(Into::into)
-    todo!()
+/// Update status of entry only if its current status is `expected`.
+/// Returns updated entry or `None` if nothing was updated.
+pub async fn update_status_if(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    expected: FileStatus,
+    status: FileStatus,
+) -> DatabaseResult<Option<File>> {
+    connection
+        .immediate_transaction(|conn| {
+            async {
+                trace!(
+                    "UPDATE SET status={} WHERE id={} AND status={}",
+                    status,
+                    pk,
+                    expected
+                );
+                diesel::update(files.find(pk).filter(dsl::status.eq(expected)))
+                    .set(dsl::status.eq(status))
+                    .get_result(conn)
+                    .await
+                    .optional()
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(Into::into)
+}
+
+/// Update store policy of entry and the moment when it expires. Returns updated entry.
+pub async fn update_store_policy(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    store_policy: file::StorePolicy,
+) -> DatabaseResult<File> {
+    let (policy, data, extra_data): (StorePolicy, _, _) = store_policy.try_into()?;
+    connection
+        .immediate_transaction(|conn| {
+            async move {
+                trace!("SELECT * WHERE id={}", pk);
+                let current = files.find(pk).first::<File>(conn).await?;
+                let mut metadata = file::FileMetadata::try_from(current)?;
+                metadata.store_policy = store_policy;
+                let expires_at = metadata.expires_at();
+                trace!(
+                    "UPDATE SET store_policy={:?}, expires_at={:?} WHERE id={}",
+                    store_policy,
+                    expires_at,
+                    pk
+                );
+                let updated = diesel::update(files.find(pk))
+                    .set((
+                        dsl::store_policy.eq(policy),
+                        dsl::store_policy_data.eq(data),
+                        dsl::store_policy_extra_data.eq(extra_data),
+                        dsl::expires_at.eq(expires_at),
+                    ))
+                    .get_result(conn)
+                    .await?;
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Update store policy of all entries with sources starting with `prefix` and the moments when
+/// they expire. Returns updated entries.
+///
+/// Entries are updated in a single transaction. The moment of expiration depends on timestamps
+/// of each entry, so it is recomputed per entry.
+pub async fn update_store_policy_by_source_prefix(
+    connection: &mut Connection,
+    prefix: &str,
+    store_policy: file::StorePolicy,
+) -> DatabaseResult<Vec<File>> {
+    let (policy, data, extra_data): (StorePolicy, _, _) = store_policy.try_into()?;
+    let pattern = source_prefix_pattern(prefix);
+    connection
+        .immediate_transaction(|conn| {
+            async move {
+                trace!(
+                    "UPDATE SET store_policy={:?} WHERE source LIKE {}",
+                    store_policy,
+                    pattern
+                );
+                let mut updated: Vec<File> = diesel::update(
+                    files
+                        .filter(dsl::source.like(&pattern).escape('\\'))
+                        // LIKE of SQLite ignores case of ASCII letters
+                        .filter(instr(dsl::source, prefix).eq(1)),
+                )
+                .set((
+                    dsl::store_policy.eq(policy),
+                    dsl::store_policy_data.eq(data),
+                    dsl::store_policy_extra_data.eq(extra_data),
+                ))
+                .get_results(conn)
+                .await?;
+                for entry in &mut updated {
+                    let expires_at = file::FileMetadata::try_from(entry.clone())?.expires_at();
+                    if entry.expires_at != expires_at {
+                        trace!(
+                            "UPDATE SET expires_at={:?} WHERE id={}",
+                            expires_at,
+                            entry.id
+                        );
+                        *entry = diesel::update(files.find(entry.id))
+                            .set(dsl::expires_at.eq(expires_at))
+                            .get_result(conn)
+                            .await?;
+                    }
+                }
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Update last used timestamp of entry to `at`, increment its number of uses and recompute the
+/// moment when it expires. Returns updated entry.
+pub async fn mark_used(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    at: DateTime<Utc>,
+) -> DatabaseResult<File> {
+    connection
+        .immediate_transaction(|conn| {
+            async move {
+                trace!("SELECT * WHERE id={}", pk);
+                let current = files.find(pk).first::<File>(conn).await?;
+                let mut metadata = file::FileMetadata::try_from(current)?;
+                metadata.last_used = at;
+                metadata.uses += 1;
+                let expires_at = metadata.expires_at();
+                trace!(
+                    "UPDATE SET last_used={}, uses=uses+1, expires_at={:?} WHERE id={}",
+                    at,
+                    expires_at,
+                    pk
+                );
+                let updated = diesel::update(files.find(pk))
+                    .set((
+                        dsl::last_used.eq(at),
+                        dsl::uses.eq(dsl::uses + 1),
+                        dsl::expires_at.eq(expires_at),
+                    ))
+                    .get_result(conn)
+                    .await?;
+                Ok(updated)
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+/// Pin or unpin entry. Returns updated entry.
+pub async fn update_pinned(
+    connection: &mut Connection,
+    pk: PrimaryKey,
+    pinned: bool,
+) -> DatabaseResult<File> {
+    connection
+        .immediate_transaction(|conn| {
+            async {
+                trace!("UPDATE SET pinned={} WHERE id={}", pinned, pk);
+                diesel::update(files.find(pk))
+                    .set(dsl::pinned.eq(pinned))
+                    .get_result(conn)
+                    .await
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(Into::into)
+}
+
+/// Get all "stale" cache entries from the database, which expire at `now` or earlier and are not
+/// pinned.
+///
+/// Uses index of the computed `expires_at` column.
+pub async fn get_all_stale(
+    connection: &mut Connection,
+    now: DateTime<Utc>,
+) -> DatabaseResult<Vec<File>> {
+    connection
+        .transaction(|conn| {
+            async move {
+                trace!("SELECT * WHERE expires_at <= {} AND NOT pinned", now);
+                files
+                    .filter(dsl::expires_at.le(now))
+                    .filter(dsl::pinned.eq(false))
+                    .get_results(conn)
+                    .await
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(Into::into)
 }
 
 #[cfg(test)]
@@ -195,7 +419,6 @@ mod tests {
     use crate::database::StorageDatabaseError;
     use crate::sqlite::error::DatabaseError;
     use crate::sqlite::fixtures::{database, database_with_single_entry, SqliteDatabaseFixture};
-    use crate::sqlite::models::StorePolicy;
     use chrono::Utc;
     use rstest::rstest;
     use tracing_test::traced_test;
@@ -215,7 +438,15 @@ mod tests {
                 last_used: Utc::now(),
                 store_policy: StorePolicy::StoreForever,
                 store_policy_data: None,
+                store_policy_extra_data: None,
                 status: FileStatus::default(),
+                validator: None,
+                negative_status: None,
+                digest: None,
+                uid: None,
+                expires_at: None,
+                pinned: false,
+                uses: 0,
             },
         )
         .await
@@ -227,6 +458,56 @@ mod tests {
         assert_eq!(entry.store_policy_data, None);
     }
 
+    #[rstest(database as db_fixture)]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_get_all_stale(#[future] db_fixture: SqliteDatabaseFixture) {
+        let now = Utc::now();
+        let mut ids = Vec::new();
+        for (n, (expires_at, pinned)) in [
+            (Some(now - chrono::TimeDelta::seconds(1)), false),
+            (Some(now), false),
+            (Some(now + chrono::TimeDelta::seconds(1)), false),
+            (None, false),
+            (Some(now - chrono::TimeDelta::seconds(1)), true),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let entry = insert(
+                db_fixture.conn().await.as_mut(),
+                NewFile {
+                    source: format!("http://localhost/{n}"),
+                    cache_path: format!("/var/cache/{n}"),
+                    filename: None,
+                    created: now,
+                    last_used: now,
+                    store_policy: StorePolicy::ExpiresAfter,
+                    store_policy_data: Some(1_000_000),
+                    store_policy_extra_data: None,
+                    status: FileStatus::default(),
+                    validator: None,
+                    negative_status: None,
+                    digest: None,
+                    uid: None,
+                    expires_at,
+                    pinned,
+                    uses: 0,
+                },
+            )
+            .await
+            .expect("add new entry");
+            ids.push(entry.id);
+        }
+        let stale = get_all_stale(db_fixture.conn().await.as_mut(), now)
+            .await
+            .expect("get stale entries");
+        let mut stale_ids = stale.iter().map(|entry| entry.id).collect::<Vec<_>>();
+        stale_ids.sort();
+        assert_eq!(stale_ids, ids[..2]);
+    }
+
     #[rstest(database_with_single_entry as fixture)]
     #[tokio::test]
     #[traced_test]
@@ -244,7 +525,15 @@ mod tests {
                 last_used: Utc::now(),
                 store_policy: StorePolicy::StoreForever,
                 store_policy_data: None,
+                store_policy_extra_data: None,
                 status: FileStatus::default(),
+                validator: None,
+                negative_status: None,
+                digest: None,
+                uid: None,
+                expires_at: None,
+                pinned: false,
+                uses: 0,
             },
         )
         .await;
@@ -269,6 +558,70 @@ mod tests {
         assert_eq!(entry, inserted_entry);
     }
 
+    #[rstest(database_with_single_entry as fixture)]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_update_store_policy(#[future] fixture: (SqliteDatabaseFixture, File)) {
+        let (db_fixture, inserted_entry) = fixture;
+        let mut conn = db_fixture.conn().await;
+        let entry = update_store_policy(
+            conn.as_mut(),
+            inserted_entry.id,
+            file::StorePolicy::ExpiresAfter {
+                duration: std::time::Duration::from_secs(1),
+            },
+        )
+        .await
+        .expect("update store policy");
+        assert_eq!(entry.store_policy, StorePolicy::ExpiresAfter);
+        assert_eq!(entry.store_policy_data, Some(1_000_000));
+        assert_eq!(
+            entry.expires_at,
+            Some(inserted_entry.created + chrono::TimeDelta::seconds(1))
+        );
+
+        let entry = update_store_policy(
+            conn.as_mut(),
+            inserted_entry.id,
+            file::StorePolicy::StoreForever,
+        )
+        .await
+        .expect("update store policy");
+        assert_eq!(entry.store_policy, StorePolicy::StoreForever);
+        assert_eq!(entry.store_policy_data, None);
+        assert_eq!(entry.expires_at, None);
+    }
+
+    #[rstest(database as db_fixture)]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_mark_used(#[future] db_fixture: SqliteDatabaseFixture) {
+        let now = Utc::now();
+        let mut conn = db_fixture.conn().await;
+        let inserted = insert(
+            conn.as_mut(),
+            NewFile {
+                store_policy: StorePolicy::ExpiresAfterUses,
+                store_policy_data: Some(1),
+                ..SqliteDatabaseFixture::default_new_entry()
+            },
+        )
+        .await
+        .expect("add new entry");
+        let entry = mark_used(conn.as_mut(), inserted.id, now)
+            .await
+            .expect("mark entry as used");
+        assert_eq!(entry.last_used, now);
+        assert_eq!(entry.uses, 1);
+        assert_eq!(entry.expires_at, Some(now));
+        let stale = get_all_stale(conn.as_mut(), now)
+            .await
+            .expect("get stale entries");
+        assert_eq!(stale, [entry]);
+    }
+
     #[rstest(database_with_single_entry as fixture)]
     #[tokio::test]
     #[traced_test]
@@ -331,7 +684,8 @@ mod tests {
             FileStatus::Pending,
             FileStatus::Ready,
             FileStatus::Corrupted,
-            FileStatus::ToRemove
+            FileStatus::ToRemove,
+            FileStatus::Partial
         )]
         status: FileStatus,
     ) {
@@ -345,6 +699,38 @@ mod tests {
         assert_eq!(entry.status, status);
     }
 
+    #[rstest(database_with_single_entry as fixture)]
+    #[case::expected_status(FileStatus::Pending, true)]
+    #[case::different_status(FileStatus::Ready, false)]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_update_status_if(
+        #[future] fixture: (SqliteDatabaseFixture, File),
+        #[case] expected: FileStatus,
+        #[case] updated: bool,
+    ) {
+        let (db_fixture, inserted_entry) = fixture;
+        let result = update_status_if(
+            db_fixture.conn().await.as_mut(),
+            inserted_entry.id,
+            expected,
+            FileStatus::Partial,
+        )
+        .await
+        .expect("update file status");
+        assert_eq!(result.is_some(), updated);
+        let entry = get(db_fixture.conn().await.as_mut(), inserted_entry.id)
+            .await
+            .unwrap();
+        let status = if updated {
+            FileStatus::Partial
+        } else {
+            FileStatus::Pending
+        };
+        assert_eq!(entry.status, status);
+    }
+
     #[rstest]
     #[tokio::test]
     #[traced_test]
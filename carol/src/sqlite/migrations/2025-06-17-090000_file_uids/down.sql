@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX `files_uid_idx`;
+ALTER TABLE `files` DROP COLUMN `uid`;
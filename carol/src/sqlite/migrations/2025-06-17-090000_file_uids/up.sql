@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- `id` is an alias of 64-bit `rowid` already, only globally unique identifiers are added
+ALTER TABLE `files` ADD COLUMN `uid` VARCHAR;
+CREATE UNIQUE INDEX `files_uid_idx` ON `files`(`uid`);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX `files_expires_at_idx`;
+UPDATE `files` SET `store_policy_data` = `store_policy_data` / 1000000;
+ALTER TABLE `files` DROP COLUMN `expires_at`;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Expiration timestamps are computed from durations in seconds before they are converted to
+-- microseconds
+ALTER TABLE `files` ADD COLUMN `expires_at` TIMESTAMPTZSQLITE;
+UPDATE `files`
+	SET `expires_at` = strftime('%Y-%m-%d %H:%M:%f+00:00', `created`, '+' || `store_policy_data` || ' seconds')
+	WHERE `store_policy` = 1;
+UPDATE `files`
+	SET `expires_at` = strftime('%Y-%m-%d %H:%M:%f+00:00', `last_used`, '+' || `store_policy_data` || ' seconds')
+	WHERE `store_policy` = 2;
+UPDATE `files` SET `store_policy_data` = `store_policy_data` * 1000000;
+CREATE INDEX `files_expires_at_idx` ON `files`(`expires_at`);
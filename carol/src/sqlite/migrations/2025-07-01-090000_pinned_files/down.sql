@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+-- Policies unknown before are replaced with expiration after creation at the same moment, with
+-- millisecond precision of julianday
+UPDATE `files`
+	SET `store_policy` = 1,
+		`store_policy_data` = CAST(round((julianday(`expires_at`) - julianday(`created`)) * 86400000) AS BIGINT) * 1000
+	WHERE `store_policy` IN (3, 4);
+ALTER TABLE `files` DROP COLUMN `pinned`;
+ALTER TABLE `files` DROP COLUMN `store_policy_extra_data`;
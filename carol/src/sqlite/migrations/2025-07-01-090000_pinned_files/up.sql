@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE `files` ADD COLUMN `store_policy_extra_data` BIGINT;
+ALTER TABLE `files` ADD COLUMN `pinned` BOOLEAN NOT NULL DEFAULT FALSE;
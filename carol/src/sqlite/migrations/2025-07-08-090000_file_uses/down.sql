@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+-- Files expiring after a number of uses are kept forever, unless they are already stale
+UPDATE `files` SET `store_policy` = 0, `store_policy_data` = NULL
+	WHERE `store_policy` = 5 AND `expires_at` IS NULL;
+UPDATE `files`
+	SET `store_policy` = 1,
+		`store_policy_data` = CAST(round((julianday(`expires_at`) - julianday(`created`)) * 86400000) AS BIGINT) * 1000
+	WHERE `store_policy` = 5;
+ALTER TABLE `files` DROP COLUMN `uses`;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE `files` ADD COLUMN `uses` BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- Assigned identifiers are kept, they are valid without this migration as well
+SELECT 1;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Files stored before globally unique identifiers were introduced get random (version 4) UUIDs
+UPDATE `files`
+	SET `uid` = lower(
+		hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2)
+		|| '-' || substr('89AB', 1 + (abs(random()) % 4), 1) || substr(hex(randomblob(2)), 2)
+		|| '-' || hex(randomblob(6))
+	)
+	WHERE `uid` IS NULL;
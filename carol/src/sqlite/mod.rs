@@ -5,6 +5,7 @@
 use std::fmt;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use diesel::{ConnectionError, ConnectionResult, SqliteConnection};
 use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
 use diesel_async::pooled_connection::deadpool::Pool;
@@ -16,17 +17,19 @@ use futures_util::future::BoxFuture;
 use futures_util::FutureExt;
 use tokio::time::{self, Duration};
 use tracing::trace;
+use uuid::Uuid;
 
 use crate::database::{StorageDatabase, StorageDatabaseExt};
-use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus};
+use crate::file::{File, FileId, FileMetadata, FileSource, FileStatus, StorePolicy};
 
 #[allow(dead_code)]
 mod api;
-mod models;
 mod schema;
 
-pub mod error;
+#[doc(no_inline)]
+pub use crate::diesel_common::error;
 
+use crate::diesel_common::models;
 use error::{ConvertStorePolicyError, DatabaseError};
 
 /// Inner SQLite connection type.
@@ -39,7 +42,7 @@ type ConnectionManager = AsyncDieselConnectionManager<Connection>;
 pub type DatabaseResult<T> = Result<T, DatabaseError>;
 
 /// Primary key type.
-type PrimaryKey = i32;
+type PrimaryKey = i64;
 
 const MIGRATIONS: EmbeddedMigrations =
     diesel_migrations::embed_migrations!("src/sqlite/migrations");
@@ -56,7 +59,7 @@ const RETRY_PERIOD: Duration = Duration::from_millis(100);
 /// - `PRAGMA busy_timeout = 10_000`
 ///
 /// We really want this to succeed, that's why we retry.
-fn establish_connection(database_url: &str) -> BoxFuture<ConnectionResult<Connection>> {
+fn establish_connection(database_url: &str) -> BoxFuture<'_, ConnectionResult<Connection>> {
     let fut = async move {
         trace!("establishing connection with {}", database_url);
         let mut connection = Connection::establish(database_url).await?;
@@ -154,6 +157,7 @@ impl SqliteStorageDatabase {
             database: self.database_url.clone(),
             status: model.status.into(),
             id: model.id.into(),
+            uid: model.uid.as_deref().map(Uuid::parse_str).transpose()?,
             metadata: FileMetadata::try_from(model)?,
         })
     }
@@ -177,6 +181,11 @@ impl StorageDatabase for SqliteStorageDatabase {
         self.database_url.clone()
     }
 
+    /// Apply pending migrations.
+    async fn setup(&self) -> DatabaseResult<()> {
+        run_migrations(&self.database_url).await
+    }
+
     async fn store(&self, metadata: FileMetadata) -> DatabaseResult<FileId> {
         let mut conn = self.pool.get().await?;
         let file = api::insert(conn.as_mut(), models::NewFile::try_from(metadata)?).await?;
@@ -206,11 +215,84 @@ impl StorageDatabaseExt for SqliteStorageDatabase {
             .collect::<Result<_, _>>()?)
     }
 
+    async fn select_by_uid(&self, uid: Uuid) -> DatabaseResult<Option<File>> {
+        let mut conn = self.pool.get().await?;
+        let file = api::get_by_uid(conn.as_mut(), &uid.to_string()).await?;
+        Ok(file.map(|file| self.model_to_file(file)).transpose()?)
+    }
+
+    async fn select_by_source_prefix(&self, prefix: &str) -> DatabaseResult<Vec<File>> {
+        let mut conn = self.pool.get().await?;
+        let files = api::get_by_source_prefix(conn.as_mut(), prefix).await?;
+        Ok(files
+            .into_iter()
+            .map(|file| self.model_to_file(file))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn select_stale(&self, now: DateTime<Utc>) -> DatabaseResult<Vec<File>> {
+        let mut conn = self.pool.get().await?;
+        let files = api::get_all_stale(conn.as_mut(), now).await?;
+        Ok(files
+            .into_iter()
+            .map(|file| self.model_to_file(file))
+            .collect::<Result<_, _>>()?)
+    }
+
     async fn update_status(&self, id: FileId, new_status: FileStatus) -> Result<File, Self::Error> {
         let mut conn = self.pool.get().await?;
         let file = api::update_status(conn.as_mut(), id.into(), new_status.into()).await?;
         Ok(self.model_to_file(file)?)
     }
+
+    async fn update_status_if(
+        &self,
+        id: FileId,
+        expected: FileStatus,
+        new_status: FileStatus,
+    ) -> Result<Option<File>, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let file =
+            api::update_status_if(conn.as_mut(), id.into(), expected.into(), new_status.into())
+                .await?;
+        Ok(file.map(|file| self.model_to_file(file)).transpose()?)
+    }
+
+    async fn update_store_policy(
+        &self,
+        id: FileId,
+        store_policy: StorePolicy,
+    ) -> Result<File, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let file = api::update_store_policy(conn.as_mut(), id.into(), store_policy).await?;
+        Ok(self.model_to_file(file)?)
+    }
+
+    async fn update_store_policy_by_source_prefix(
+        &self,
+        prefix: &str,
+        store_policy: StorePolicy,
+    ) -> Result<Vec<File>, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let files =
+            api::update_store_policy_by_source_prefix(conn.as_mut(), prefix, store_policy).await?;
+        Ok(files
+            .into_iter()
+            .map(|file| self.model_to_file(file))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn mark_used(&self, id: FileId, at: DateTime<Utc>) -> Result<File, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let file = api::mark_used(conn.as_mut(), id.into(), at).await?;
+        Ok(self.model_to_file(file)?)
+    }
+
+    async fn update_pinned(&self, id: FileId, pinned: bool) -> Result<File, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let file = api::update_pinned(conn.as_mut(), id.into(), pinned).await?;
+        Ok(self.model_to_file(file)?)
+    }
 }
 
 /// Database [`rstest`] fixtures. Helps in testing database-related code.
@@ -246,7 +328,15 @@ pub mod fixtures {
                 last_used: DateTime::<Utc>::MIN_UTC,
                 store_policy: models::StorePolicy::StoreForever,
                 store_policy_data: None,
+                store_policy_extra_data: None,
                 status: models::FileStatus::Pending,
+                validator: None,
+                negative_status: None,
+                digest: None,
+                uid: None,
+                expires_at: None,
+                pinned: false,
+                uses: 0,
             }
         }
 
@@ -316,11 +406,13 @@ pub mod fixtures {
 #[cfg(test)]
 mod tests {
     use super::fixtures::{database, database_with_single_entry, SqliteDatabaseFixture};
-    use super::{establish_connection, models};
+    use super::{establish_connection, models, Connection, MIGRATIONS};
     use crate::database::{StorageDatabase, StorageDatabaseExt};
     use crate::error::NonUtf8PathError;
     use crate::file::{FileMetadata, FileSource, FileStatus, StorePolicy};
     use chrono::Utc;
+    use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
+    use diesel_migrations::MigrationHarness;
     use rstest::rstest;
     use std::path::PathBuf;
     use tracing_test::traced_test;
@@ -373,6 +465,44 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[tokio::test]
+    #[awt]
+    async fn test_conformance(#[future] database: SqliteDatabaseFixture) {
+        crate::conformance::run(&database.database).await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_backfill_uids(
+        #[future] database_with_single_entry: (SqliteDatabaseFixture, models::File),
+    ) {
+        let (fixture, inserted) = database_with_single_entry;
+        assert_eq!(inserted.uid, None);
+
+        // Run the backfill again, as if the file was stored before it
+        let connection = establish_connection(&fixture.database_url()).await.unwrap();
+        let mut async_wrapper: AsyncConnectionWrapper<Connection> =
+            AsyncConnectionWrapper::from(connection);
+        tokio::task::spawn_blocking(move || {
+            async_wrapper.revert_last_migration(MIGRATIONS).unwrap();
+            async_wrapper.run_pending_migrations(MIGRATIONS).unwrap();
+        })
+        .await
+        .unwrap();
+
+        let file = fixture.database.get(inserted.id.into()).await.expect("get");
+        let uid = file.uid.expect("backfilled uid");
+        assert_eq!(uid.get_version_num(), 4);
+        assert_eq!(uid.get_variant(), uuid::Variant::RFC4122);
+        assert_eq!(
+            fixture.database.select_by_uid(uid).await.expect("select"),
+            Some(file)
+        );
+    }
+
     #[rstest]
     #[tokio::test]
     #[traced_test]
@@ -388,6 +518,11 @@ mod tests {
                 store_policy: StorePolicy::StoreForever,
                 created: now,
                 last_used: now,
+                validator: None,
+                negative_status: None,
+                digest: None,
+                pinned: false,
+                uses: 0,
             })
             .await
             .expect("store");
@@ -402,7 +537,7 @@ mod tests {
     #[awt]
     async fn test_get(
         #[future] database_with_single_entry: (SqliteDatabaseFixture, models::File),
-        #[case] id: Option<i32>,
+        #[case] id: Option<i64>,
     ) {
         let (fixture, inserted) = database_with_single_entry;
         let id = if let Some(id) = id { id } else { inserted.id };
@@ -417,7 +552,7 @@ mod tests {
     #[awt]
     async fn test_remove(
         #[future] database_with_single_entry: (SqliteDatabaseFixture, models::File),
-        #[case] id: Option<i32>,
+        #[case] id: Option<i64>,
     ) {
         let (fixture, inserted) = database_with_single_entry;
         let id = if let Some(id) = id { id } else { inserted.id };
@@ -467,4 +602,27 @@ mod tests {
             .expect("update status");
         assert_eq!(updated.status, FileStatus::ToRemove);
     }
+
+    #[rstest]
+    #[tokio::test]
+    #[traced_test]
+    #[awt]
+    async fn test_update_status_if(
+        #[future] database_with_single_entry: (SqliteDatabaseFixture, models::File),
+    ) {
+        let (fixture, inserted) = database_with_single_entry;
+        let updated = fixture
+            .database
+            .update_status_if(inserted.id.into(), FileStatus::Pending, FileStatus::Partial)
+            .await
+            .expect("update status")
+            .expect("status updated");
+        assert_eq!(updated.status, FileStatus::Partial);
+        let updated = fixture
+            .database
+            .update_status_if(inserted.id.into(), FileStatus::Pending, FileStatus::Ready)
+            .await
+            .expect("update status");
+        assert!(updated.is_none());
+    }
 }
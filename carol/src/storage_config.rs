@@ -34,4 +34,19 @@ pub struct StorageConfig {
     ///
     /// Default eviction policy is [`EvictionPolicy::Lru`].
     pub eviction_policy: EvictionPolicy,
+
+    /// Maximum number of files written at the same time.
+    ///
+    /// Writes exceeding the limit wait for running writes to finish in order of their start. The
+    /// limit is shared between clones of storage manager. Default is no limit.
+    #[serde(default)]
+    pub max_concurrent_writes: Option<usize>,
+
+    /// Maximum total write speed in bytes per second.
+    ///
+    /// The bandwidth is split equally between running writes of storage manager and its clones.
+    /// Each write is throttled with its own token bucket, which allows bursts of up to one second
+    /// worth of its share. Default is no limit.
+    #[serde(default)]
+    pub max_write_bandwidth: Option<u64>,
 }
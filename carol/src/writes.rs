@@ -0,0 +1,195 @@
+//! Tracking of files being written by this process.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::file::FileId;
+
+/// Progress of a file being written into storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteProgress {
+    /// Number of bytes of the file written so far, including content written before the write
+    /// was resumed.
+    pub written: u64,
+
+    /// Expected size of the complete file, if known.
+    pub expected: Option<u64>,
+
+    /// Size of the file when the write started. Non-zero if a partial file is resumed.
+    pub resumed_from: u64,
+
+    /// Time when the write started.
+    pub started: Instant,
+}
+
+impl WriteProgress {
+    /// Create progress of a write, which is not started yet.
+    pub fn new(expected: Option<u64>) -> Self {
+        Self {
+            written: 0,
+            expected,
+            resumed_from: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Create channel to receive progress of a write with `expected` size.
+    ///
+    /// See [`StorageManager::add_file_from_stream_with_progress`](crate::StorageManager::add_file_from_stream_with_progress).
+    pub fn channel(
+        expected: Option<u64>,
+    ) -> (watch::Sender<WriteProgress>, watch::Receiver<WriteProgress>) {
+        watch::channel(Self::new(expected))
+    }
+
+    /// Time elapsed since the write started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Average write speed in bytes per second since the write started.
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            (self.written - self.resumed_from) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Written fraction of the file in `0.0..=1.0` range, if expected size is known.
+    pub fn fraction(&self) -> Option<f64> {
+        match self.expected? {
+            0 => Some(1.0),
+            expected => Some((self.written as f64 / expected as f64).min(1.0)),
+        }
+    }
+}
+
+/// Registry of files being written by storage manager.
+///
+/// Readers of a file, which is being written, are notified when new content is written, so they
+/// don't need to poll the database.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Writes {
+    /// Receivers of write progress by file ID.
+    files: Arc<Mutex<HashMap<FileId, watch::Receiver<WriteProgress>>>>,
+}
+
+impl Writes {
+    /// Register write of the file, which has `resumed_from` bytes written already. Write ends when
+    /// returned guard is dropped.
+    ///
+    /// Progress is reported to `sender` if it's provided. Its expected size is kept.
+    pub fn start(
+        &self,
+        id: FileId,
+        resumed_from: u64,
+        sender: Option<watch::Sender<WriteProgress>>,
+    ) -> WriteGuard {
+        let sender = sender.unwrap_or_else(|| WriteProgress::channel(None).0);
+        sender.send_modify(|progress| {
+            *progress = WriteProgress {
+                written: resumed_from,
+                resumed_from,
+                ..WriteProgress::new(progress.expected)
+            }
+        });
+        self.files.lock().unwrap().insert(id, sender.subscribe());
+        WriteGuard {
+            writes: self.clone(),
+            id,
+            sender,
+        }
+    }
+
+    /// Follow write of the file.
+    ///
+    /// Returns `None` if the file is not being written by this process. Returned receiver is
+    /// notified on every written chunk and is closed when writing ends.
+    pub fn follow(&self, id: FileId) -> Option<watch::Receiver<WriteProgress>> {
+        self.files.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// Guard of a file write registered in [`Writes`].
+pub(crate) struct WriteGuard {
+    writes: Writes,
+    id: FileId,
+    sender: watch::Sender<WriteProgress>,
+}
+
+impl WriteGuard {
+    /// Notify readers that `len` more bytes were written.
+    pub fn advance(&self, len: usize) {
+        self.sender
+            .send_modify(|progress| progress.written += len as u64);
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.writes.files.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rstest::rstest;
+
+    use super::{WriteProgress, Writes};
+    use crate::file::FileId;
+
+    #[test]
+    fn test_progress() {
+        let writes = Writes::default();
+        let id = FileId::from(1i64);
+        let (sender, receiver) = WriteProgress::channel(Some(10));
+        let guard = writes.start(id, 4, Some(sender));
+        let follow = writes.follow(id).unwrap();
+        guard.advance(2);
+        for progress in [*receiver.borrow(), *follow.borrow()] {
+            assert_eq!(progress.written, 6);
+            assert_eq!(progress.expected, Some(10));
+            assert_eq!(progress.resumed_from, 4);
+        }
+        drop(guard);
+        assert!(writes.follow(id).is_none());
+        assert!(receiver.has_changed().is_err());
+    }
+
+    #[rstest]
+    #[case::unknown(None, 5, None)]
+    #[case::empty(Some(0), 0, Some(1.0))]
+    #[case::half(Some(10), 5, Some(0.5))]
+    #[case::exceeded(Some(10), 15, Some(1.0))]
+    #[trace]
+    fn test_fraction(
+        #[case] expected: Option<u64>,
+        #[case] written: u64,
+        #[case] fraction: Option<f64>,
+    ) {
+        let progress = WriteProgress {
+            written,
+            ..WriteProgress::new(expected)
+        };
+        assert_eq!(progress.fraction(), fraction);
+    }
+
+    #[test]
+    fn test_throughput() {
+        let progress = WriteProgress {
+            written: 3000,
+            resumed_from: 1000,
+            started: Instant::now() - Duration::from_secs(2),
+            ..WriteProgress::new(None)
+        };
+        let throughput = progress.throughput();
+        assert!(throughput > 900.0 && throughput <= 1000.0, "{throughput}");
+    }
+}